@@ -1,11 +1,14 @@
-use std::env;
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::time::Duration;
 
-use config::File;
-use config::{Config, ConfigError};
+use anyhow::Context;
+use config::{Config, ConfigError, Map, Value, ValueKind};
+use config::{Environment, File};
 use serde::Deserialize;
 
+use crate::config::cli::Args;
+use crate::tools::auth::Role;
 use crate::tools::json::deserialize_duration;
 
 #[derive(Debug, Deserialize)]
@@ -13,34 +16,146 @@ pub struct AppConfig {
     pub server: Server,
     pub db: Database,
     pub redis: Redis,
+    pub batching: Batching,
+    pub logging: Logging,
+    pub reload: Reload,
+    pub shutdown: Shutdown,
+    pub health: Health,
+    pub auth: Auth,
+    pub rate_limit: RateLimit,
+    pub load_shedding: LoadShedding,
+    pub telemetry: Telemetry,
+    pub metrics: Metrics,
+    pub breakers: Breakers,
+
+    /// every setting the config was loaded with, by dotted key, to tell what a reload changed
+    #[serde(skip)]
+    pub settings: BTreeMap<String, String>,
+}
+
+/// The subset of [`AppConfig`] that can be applied to a running instance (see
+/// [`crate::config::reload`]); everything else only takes effect after a restart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reloadable {
+    pub db_max_connections: usize,
+    pub db_max_idle: Duration,
+    pub redis_ttl: Duration,
+    pub log_filter: String,
+    pub rate_limit_rate: u32,
+    pub rate_limit_burst: u32,
 }
 
+/// keys of the settings in [`Reloadable`]
+const RELOADABLE_KEYS: &[&str] = &[
+    "db.max_connections",
+    "db.max_idle",
+    "redis.ttl",
+    "logging.filter",
+    "rate_limit.rate",
+    "rate_limit.burst",
+];
+
 impl AppConfig {
-    pub fn new() -> Self {
-        Self::load().expect("failed to load config file")
+    pub async fn new(args: &Args) -> anyhow::Result<Self> {
+        let conf = Self::load(args).context("failed to load config files")?;
+        conf.validate().await?;
+
+        Ok(conf)
     }
 
-    fn load() -> Result<AppConfig, ConfigError> {
-        let path = Path::new("src/config/env");
-        let profile = env::var("RUST_ENV").unwrap_or_else(|_| String::from("local"));
+    /// Sources are layered in this order, later ones taking precedence:
+    ///
+    /// 1. `<config_dir>/defaults.yml`
+    /// 2. `<config_dir>/<profile>.yml` (profile from `--profile` or `RUST_ENV`)
+    /// 3. the file given by `--config`, if any
+    /// 4. `APP__` prefixed environment variables, nested with `__` (e.g. `APP__DB__HOST`)
+    /// 5. command line flags (`--port`, `--db-host`, ...)
+    fn load(args: &Args) -> Result<AppConfig, ConfigError> {
+        let builder = args
+            .config_files()
+            .into_iter()
+            .fold(Config::builder(), |builder, file| {
+                builder.add_source(File::from(file))
+            });
 
-        let result = Config::builder()
-            .add_source(File::from(path.join("defaults.yml")))
-            .add_source(File::from(path.join(format!("{}.yml", profile))))
+        let result = builder
+            .add_source(
+                Environment::with_prefix("APP")
+                    .separator("__")
+                    .try_parsing(true),
+            )
+            .set_override_option("server.port", args.port)?
+            .set_override_option("db.host", args.db_host.clone())?
+            .set_override_option("db.port", args.db_port)?
+            .set_override_option("redis.host", args.redis_host.clone())?
+            .set_override_option("redis.port", args.redis_port)?
             .build()?;
 
-        let conf = result.try_deserialize()?;
+        let mut settings = BTreeMap::new();
+        let table = result.clone().try_deserialize::<Map<String, Value>>()?;
+        flatten("", ValueKind::Table(table), &mut settings);
+
+        let mut conf: AppConfig = result.try_deserialize()?;
+        conf.settings = settings;
 
         Ok(conf)
     }
+
+    pub fn reloadable(&self) -> Reloadable {
+        Reloadable {
+            db_max_connections: self.db.max_connections,
+            db_max_idle: self.db.max_idle,
+            redis_ttl: self.redis.ttl,
+            log_filter: self.logging.filter.clone(),
+            rate_limit_rate: self.rate_limit.rate,
+            rate_limit_burst: self.rate_limit.burst,
+        }
+    }
+
+    /// keys of the settings that differ from `settings` but only take effect after a restart
+    pub fn restart_required(&self, settings: &BTreeMap<String, String>) -> Vec<String> {
+        changed_keys(settings, &self.settings)
+            .filter(|key| !RELOADABLE_KEYS.contains(&key.as_str()))
+            .collect()
+    }
 }
 
-impl Default for AppConfig {
-    fn default() -> Self {
-        Self::new()
+fn flatten(prefix: &str, value: ValueKind, settings: &mut BTreeMap<String, String>) {
+    let key = |name: &dyn std::fmt::Display| match prefix {
+        "" => name.to_string(),
+        _ => format!("{}.{}", prefix, name),
+    };
+
+    match value {
+        ValueKind::Table(table) => {
+            for (name, value) in table {
+                flatten(&key(&name), value.kind, settings);
+            }
+        }
+        ValueKind::Array(array) => {
+            for (index, value) in array.into_iter().enumerate() {
+                flatten(&key(&index), value.kind, settings);
+            }
+        }
+        value => {
+            settings.insert(prefix.to_string(), value.to_string());
+        }
     }
 }
 
+fn changed_keys<'a>(
+    before: &'a BTreeMap<String, String>,
+    after: &'a BTreeMap<String, String>,
+) -> impl Iterator<Item = String> + 'a {
+    let removed = before.keys().filter(|key| !after.contains_key(*key));
+    let changed = after
+        .iter()
+        .filter(|(key, value)| before.get(*key) != Some(value))
+        .map(|(key, _)| key);
+
+    removed.chain(changed).cloned()
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Server {
     pub port: u16,
@@ -48,12 +163,50 @@ pub struct Server {
 
 #[derive(Debug, Deserialize)]
 pub struct Database {
+    /// `http`, `https` or `libsql` (an alias for `https`)
+    pub scheme: String,
     pub host: String,
     pub port: u16,
+
+    /// sqld JWT, `APP__DB__AUTH_TOKEN` keeps it out of the config files
+    pub auth_token: Option<String>,
+
+    /// file holding the sqld JWT, as an alternative to `auth_token`
+    pub auth_token_file: Option<PathBuf>,
+
+    /// PEM bundle trusted in addition to the system roots
+    pub ca_cert: Option<PathBuf>,
+
+    /// reloadable
     pub max_connections: usize,
 
+    /// reloadable
     #[serde(deserialize_with = "deserialize_duration")]
     pub max_idle: Duration,
+
+    /// how long a request waits for a pooled connection before failing with 503
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub acquire_timeout: Duration,
+
+    /// statements taking longer than this are logged, without their literal values
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub slow_query_threshold: Duration,
+
+    pub retry: Retry,
+}
+
+/// retries of transient database errors (see [`crate::tools::retry`])
+#[derive(Debug, Deserialize)]
+pub struct Retry {
+    /// attempts in total, 1 disables retries
+    pub max_attempts: u32,
+
+    /// wait before the first retry, doubled on every following one
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub backoff: Duration,
+
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub max_backoff: Duration,
 }
 
 #[derive(Debug, Deserialize)]
@@ -61,6 +214,235 @@ pub struct Redis {
     pub host: String,
     pub port: u16,
 
+    /// reloadable
     #[serde(deserialize_with = "deserialize_duration")]
     pub ttl: Duration,
+
+    /// how long a request waits for a lock before failing with 503
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub acquire_timeout: Duration,
+
+    /// when redis can't be reached (breaker open or connection errors), write transactions
+    /// without the lock, relying on their `IMMEDIATE` database transaction, instead of failing.
+    /// Readiness then only reports redis as degraded.
+    pub fallback: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Batching {
+    pub enabled: bool,
+    pub max_size: usize,
+
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub window: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Logging {
+    /// `EnvFilter` directives (reloadable)
+    pub filter: String,
+
+    /// `pretty` for humans, `json` (one object per line) for log pipelines
+    pub format: String,
+
+    /// also write logs to rotated files
+    pub file: Option<LogFile>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogFile {
+    pub dir: PathBuf,
+
+    /// files are named `<prefix>.<date>.log`
+    pub prefix: String,
+
+    /// `minutely`, `hourly`, `daily` or `never`
+    pub rotation: String,
+
+    /// older files are deleted past this count
+    pub max_files: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Reload {
+    /// also reload when a config file changes, not only on SIGHUP
+    pub watch_files: bool,
+
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub interval: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Shutdown {
+    /// how long `/health` reports unavailable before the server stops accepting connections
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub drain: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Health {
+    /// how long readiness probe results are reused
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub cache_ttl: Duration,
+
+    /// upper bound for each dependency probe
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Auth {
+    /// when disabled every request is served as an admin
+    pub enabled: bool,
+
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+
+    /// HS256 secret JWTs are signed with, `APP__AUTH__JWT_SECRET` keeps it out of the config files
+    pub jwt_secret: Option<String>,
+
+    /// expected `iss` claim, not checked when unset
+    pub jwt_issuer: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiKey {
+    /// identifies the caller in the logs
+    pub name: String,
+    pub key: String,
+
+    #[serde(default)]
+    pub role: Role,
+
+    /// client ids a `client` key is allowed to access
+    #[serde(default)]
+    pub clients: Vec<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RateLimit {
+    pub enabled: bool,
+
+    /// tokens added to each bucket per second (reloadable)
+    pub rate: u32,
+
+    /// bucket capacity, how many requests can be made in a burst (reloadable)
+    pub burst: u32,
+
+    /// also key buckets by the authenticated caller, not only by client id
+    pub per_principal: bool,
+
+    /// `memory` keeps buckets per instance, `redis` shares them between instances
+    pub backend: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoadShedding {
+    pub deadlines: Deadlines,
+    pub concurrency: Concurrency,
+}
+
+/// time a request may spend waiting for a database connection, a lock or a place in a batch,
+/// per route, before it fails with 503
+#[derive(Debug, Deserialize)]
+pub struct Deadlines {
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub transaction: Duration,
+
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub statement: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Concurrency {
+    pub enabled: bool,
+    pub initial: usize,
+    pub min: usize,
+    pub max: usize,
+
+    /// latency above which the limit shrinks
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub target_latency: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Telemetry {
+    /// exports spans over OTLP
+    pub enabled: bool,
+
+    /// OTLP gRPC endpoint of the collector
+    pub endpoint: String,
+    pub service_name: String,
+
+    /// fraction of the traces started here that are exported
+    pub sample_ratio: f64,
+
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Metrics {
+    /// upper bounds, in seconds, of the latency histogram buckets
+    pub buckets: Vec<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Breakers {
+    pub db: Breaker,
+    pub redis: Breaker,
+}
+
+/// fails calls to a dependency fast once it keeps failing (see [`crate::tools::breaker`])
+#[derive(Debug, Deserialize)]
+pub struct Breaker {
+    pub enabled: bool,
+
+    /// consecutive failures that open the breaker
+    pub failure_threshold: u32,
+
+    /// how long the breaker stays open before letting probes through
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub open_duration: Duration,
+
+    /// calls let through while half open
+    pub half_open_probes: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use config::FileFormat;
+
+    use super::*;
+
+    fn settings(yaml: &str) -> BTreeMap<String, String> {
+        let table = Config::builder()
+            .add_source(File::from_str(yaml, FileFormat::Yaml))
+            .build()
+            .and_then(|conf| conf.try_deserialize::<Map<String, Value>>())
+            .unwrap();
+
+        let mut settings = BTreeMap::new();
+        flatten("", ValueKind::Table(table), &mut settings);
+        settings
+    }
+
+    #[test]
+    fn flattens_nested_settings() {
+        let settings = settings("db:\n  port: 8080\nauth:\n  api_keys:\n    - key: a\n");
+
+        assert_eq!(settings["db.port"], "8080");
+        assert_eq!(settings["auth.api_keys.0.key"], "a");
+    }
+
+    #[test]
+    fn lists_changed_and_removed_keys() {
+        let before = settings("db:\n  port: 8080\n  max_idle: 1s\nredis:\n  host: a\n");
+        let after = settings("db:\n  port: 9090\n  max_idle: 1s\nserver:\n  port: 1\n");
+
+        assert_eq!(
+            changed_keys(&before, &after).collect::<Vec<_>>(),
+            vec!["redis.host", "db.port", "server.port"]
+        );
+    }
 }
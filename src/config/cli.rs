@@ -0,0 +1,54 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+/// Command line flags, see [`crate::config::app_config::AppConfig::load`] for how they are
+/// layered on top of the config files and the environment
+#[derive(Debug, Clone, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// directory holding `defaults.yml` and the `<profile>.yml` files
+    #[arg(long, env = "APP_CONFIG_DIR", default_value = "src/config/env")]
+    pub config_dir: PathBuf,
+
+    /// config profile, selects `<config_dir>/<profile>.yml`
+    #[arg(long, env = "RUST_ENV", default_value = "local")]
+    pub profile: String,
+
+    /// extra config file applied on top of the profile
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// overrides `server.port`
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// overrides `db.host`
+    #[arg(long)]
+    pub db_host: Option<String>,
+
+    /// overrides `db.port`
+    #[arg(long)]
+    pub db_port: Option<u16>,
+
+    /// overrides `redis.host`
+    #[arg(long)]
+    pub redis_host: Option<String>,
+
+    /// overrides `redis.port`
+    #[arg(long)]
+    pub redis_port: Option<u16>,
+}
+
+impl Args {
+    /// config files in the order they are layered, later ones taking precedence
+    pub fn config_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![
+            self.config_dir.join("defaults.yml"),
+            self.config_dir.join(format!("{}.yml", self.profile)),
+        ];
+        files.extend(self.config.clone());
+
+        files
+    }
+}
@@ -1 +1,4 @@
 pub mod app_config;
+pub mod cli;
+pub mod reload;
+pub mod validation;
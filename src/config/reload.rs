@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use crate::config::app_config::{AppConfig, Reloadable};
+use crate::config::cli::Args;
+use crate::tools::db::PooledLibsqlDatabase;
+use crate::tools::locker::Locker;
+use crate::tools::logging::{self, LogHandle};
+use crate::tools::rate_limit::RateLimiter;
+
+/// Re-reads the configuration on SIGHUP (and, if enabled, when a config file changes) and
+/// applies its [`Reloadable`] part to the live components. Requests in flight are not affected.
+pub struct ConfigReloader {
+    args: Args,
+    db: Arc<PooledLibsqlDatabase>,
+    locker: Arc<Locker>,
+    rate_limiter: Arc<RateLimiter>,
+    log_handle: LogHandle,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        args: Args,
+        db: Arc<PooledLibsqlDatabase>,
+        locker: Arc<Locker>,
+        rate_limiter: Arc<RateLimiter>,
+        log_handle: LogHandle,
+    ) -> Self {
+        Self {
+            args,
+            db,
+            locker,
+            rate_limiter,
+            log_handle,
+        }
+    }
+
+    pub fn spawn(self, conf: &AppConfig) {
+        let current = conf.reloadable();
+        // what the instance runs with until a restart, whatever is reloaded
+        let settings = conf.settings.clone();
+        // the interval is only validated, and only needed, when config files are watched
+        let mut ticker = conf.reload.watch_files.then(|| {
+            let mut ticker = interval(conf.reload.interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            ticker
+        });
+
+        tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(err) => {
+                    tracing::warn!("config reload disabled, cannot listen for SIGHUP: {}", err);
+                    return;
+                }
+            };
+
+            let mut current = current;
+            let mut last_modified = self.last_modified();
+
+            loop {
+                tokio::select! {
+                    _ = hangup.recv() => tracing::info!("SIGHUP received, reloading config"),
+                    _ = next_tick(&mut ticker) => {
+                        let modified = self.last_modified();
+                        if modified == last_modified {
+                            continue;
+                        }
+
+                        last_modified = modified;
+                        tracing::info!("config files changed, reloading config");
+                    }
+                }
+
+                if let Some(reloaded) = self.reload(&current, &settings).await {
+                    current = reloaded;
+                }
+            }
+        });
+    }
+
+    async fn reload(
+        &self,
+        current: &Reloadable,
+        settings: &BTreeMap<String, String>,
+    ) -> Option<Reloadable> {
+        let conf = match AppConfig::new(&self.args).await {
+            Ok(conf) => conf,
+            Err(err) => {
+                tracing::error!(
+                    "config reload rejected, keeping current settings: {:#}",
+                    err
+                );
+                return None;
+            }
+        };
+
+        let ignored = conf.restart_required(settings);
+        if !ignored.is_empty() {
+            tracing::warn!(
+                "config changes that only apply after a restart were ignored: {}",
+                ignored.join(", ")
+            );
+        }
+
+        let next = conf.reloadable();
+        if &next == current {
+            tracing::info!("no reloadable setting changed");
+            return None;
+        }
+
+        self.db.set_max_connections(next.db_max_connections);
+        self.db.set_max_idle(next.db_max_idle);
+        self.locker.set_default_ttl(next.redis_ttl);
+        self.rate_limiter
+            .set_limits(next.rate_limit_rate, next.rate_limit_burst);
+
+        if let Err(err) = logging::set_filter(&self.log_handle, &next.log_filter) {
+            tracing::error!("failed to apply log filter: {:#}", err);
+        }
+
+        tracing::info!("config reloaded: {:?}", next);
+
+        Some(next)
+    }
+
+    fn last_modified(&self) -> Vec<Option<SystemTime>> {
+        self.args
+            .config_files()
+            .iter()
+            .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+/// never completes when config files are not watched
+async fn next_tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,370 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use tokio::net::lookup_host;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::app_config::AppConfig;
+use crate::tools::logging::{LOG_FORMATS, LOG_ROTATIONS};
+
+const DB_SCHEMES: &[&str] = &["http", "https", "libsql"];
+const RATE_LIMIT_BACKENDS: &[&str] = &["memory", "redis"];
+const MAX_IDLE_LIMIT: Duration = Duration::from_secs(60 * 60);
+const LOCK_TTL_LIMIT: Duration = Duration::from_secs(60);
+const BATCH_WINDOW_LIMIT: Duration = Duration::from_secs(1);
+const DRAIN_LIMIT: Duration = Duration::from_secs(60);
+
+/// every problem found while validating the configuration, reported at once
+#[derive(Debug, thiserror::Error)]
+pub struct InvalidConfig(pub Vec<ConfigProblem>);
+
+#[derive(Debug)]
+pub struct ConfigProblem {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl Display for InvalidConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid configuration ({} problems)", self.0.len())?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}: {}", problem.field, problem.message)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AppConfig {
+    pub async fn validate(&self) -> Result<(), InvalidConfig> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, field: &'static str, message: String| {
+            if !ok {
+                problems.push(ConfigProblem { field, message });
+            }
+        };
+
+        check(
+            DB_SCHEMES.contains(&self.db.scheme.as_str()),
+            "db.scheme",
+            format!("must be one of {:?}", DB_SCHEMES),
+        );
+        check(
+            self.db.auth_token.is_none() || self.db.auth_token_file.is_none(),
+            "db.auth_token_file",
+            "cannot be set together with db.auth_token".into(),
+        );
+        for (field, file) in [
+            ("db.auth_token_file", &self.db.auth_token_file),
+            ("db.ca_cert", &self.db.ca_cert),
+        ] {
+            if let Some(file) = file {
+                check(
+                    file.is_file(),
+                    field,
+                    format!("'{}' is not a readable file", file.display()),
+                );
+            }
+        }
+
+        check(self.server.port != 0, "server.port", "must not be 0".into());
+        check(self.db.port != 0, "db.port", "must not be 0".into());
+        check(self.redis.port != 0, "redis.port", "must not be 0".into());
+
+        check(
+            self.db.max_connections > 0,
+            "db.max_connections",
+            "must be greater than 0".into(),
+        );
+        check(
+            !self.db.max_idle.is_zero() && self.db.max_idle <= MAX_IDLE_LIMIT,
+            "db.max_idle",
+            format!("must be positive and at most {:?}", MAX_IDLE_LIMIT),
+        );
+        check(
+            !self.redis.ttl.is_zero() && self.redis.ttl <= LOCK_TTL_LIMIT,
+            "redis.ttl",
+            format!("must be positive and at most {:?}", LOCK_TTL_LIMIT),
+        );
+
+        if self.batching.enabled {
+            check(
+                self.batching.max_size > 0,
+                "batching.max_size",
+                "must be greater than 0".into(),
+            );
+            check(
+                !self.batching.window.is_zero() && self.batching.window <= BATCH_WINDOW_LIMIT,
+                "batching.window",
+                format!("must be positive and at most {:?}", BATCH_WINDOW_LIMIT),
+            );
+        }
+
+        if let Err(err) = EnvFilter::try_new(&self.logging.filter) {
+            check(
+                false,
+                "logging.filter",
+                format!("invalid directives: {}", err),
+            );
+        }
+        check(
+            LOG_FORMATS.contains(&self.logging.format.as_str()),
+            "logging.format",
+            format!("must be one of {:?}", LOG_FORMATS),
+        );
+        if let Some(file) = &self.logging.file {
+            check(
+                LOG_ROTATIONS.contains(&file.rotation.as_str()),
+                "logging.file.rotation",
+                format!("must be one of {:?}", LOG_ROTATIONS),
+            );
+            check(
+                file.max_files > 0,
+                "logging.file.max_files",
+                "must be greater than 0".into(),
+            );
+            check(
+                !file.prefix.is_empty(),
+                "logging.file.prefix",
+                "must not be empty".into(),
+            );
+        }
+
+        check(
+            self.db.retry.max_attempts > 0,
+            "db.retry.max_attempts",
+            "must be greater than 0".into(),
+        );
+        check(
+            self.db.retry.backoff <= self.db.retry.max_backoff,
+            "db.retry.backoff",
+            "must be at most db.retry.max_backoff".into(),
+        );
+
+        check(
+            self.shutdown.drain <= DRAIN_LIMIT,
+            "shutdown.drain",
+            format!("must be at most {:?}", DRAIN_LIMIT),
+        );
+
+        for (field, timeout) in [
+            ("db.acquire_timeout", self.db.acquire_timeout),
+            ("redis.acquire_timeout", self.redis.acquire_timeout),
+            (
+                "load_shedding.deadlines.transaction",
+                self.load_shedding.deadlines.transaction,
+            ),
+            (
+                "load_shedding.deadlines.statement",
+                self.load_shedding.deadlines.statement,
+            ),
+        ] {
+            check(!timeout.is_zero(), field, "must be positive".into());
+        }
+
+        let concurrency = &self.load_shedding.concurrency;
+        if concurrency.enabled {
+            check(
+                0 < concurrency.min
+                    && concurrency.min <= concurrency.initial
+                    && concurrency.initial <= concurrency.max,
+                "load_shedding.concurrency",
+                "must satisfy 0 < min <= initial <= max".into(),
+            );
+            check(
+                !concurrency.target_latency.is_zero(),
+                "load_shedding.concurrency.target_latency",
+                "must be positive".into(),
+            );
+        }
+
+        if self.telemetry.enabled {
+            check(
+                self.telemetry.endpoint.starts_with("http://")
+                    || self.telemetry.endpoint.starts_with("https://"),
+                "telemetry.endpoint",
+                "must be an http(s) url".into(),
+            );
+            check(
+                (0.0..=1.0).contains(&self.telemetry.sample_ratio),
+                "telemetry.sample_ratio",
+                "must be between 0 and 1".into(),
+            );
+        }
+
+        let buckets = &self.metrics.buckets;
+        check(
+            !buckets.is_empty()
+                && buckets[0] > 0.0
+                && buckets.windows(2).all(|pair| pair[0] < pair[1]),
+            "metrics.buckets",
+            "must be positive and strictly increasing".into(),
+        );
+
+        for (field, breaker) in [
+            ("breakers.db", &self.breakers.db),
+            ("breakers.redis", &self.breakers.redis),
+        ] {
+            if breaker.enabled {
+                check(
+                    breaker.failure_threshold > 0
+                        && breaker.half_open_probes > 0
+                        && !breaker.open_duration.is_zero(),
+                    field,
+                    "failure_threshold, half_open_probes and open_duration must be positive".into(),
+                );
+            }
+        }
+
+        check(
+            !self.health.timeout.is_zero(),
+            "health.timeout",
+            "must be positive".into(),
+        );
+
+        if self.reload.watch_files {
+            check(
+                !self.reload.interval.is_zero(),
+                "reload.interval",
+                "must be positive".into(),
+            );
+        }
+
+        if self.auth.enabled {
+            check(
+                !self.auth.api_keys.is_empty() || self.auth.jwt_secret.is_some(),
+                "auth",
+                "needs api_keys or a jwt_secret when enabled".into(),
+            );
+        }
+        check(
+            self.auth
+                .api_keys
+                .iter()
+                .all(|api_key| !api_key.key.is_empty()),
+            "auth.api_keys",
+            "keys must not be empty".into(),
+        );
+        check(
+            self.auth.jwt_secret.as_ref().is_none_or(|s| !s.is_empty()),
+            "auth.jwt_secret",
+            "must not be empty".into(),
+        );
+
+        check(
+            RATE_LIMIT_BACKENDS.contains(&self.rate_limit.backend.as_str()),
+            "rate_limit.backend",
+            format!("must be one of {:?}", RATE_LIMIT_BACKENDS),
+        );
+        check(
+            self.rate_limit.rate > 0,
+            "rate_limit.rate",
+            "must be greater than 0".into(),
+        );
+        check(
+            self.rate_limit.burst > 0,
+            "rate_limit.burst",
+            "must be greater than 0".into(),
+        );
+
+        for (field, host, port) in [
+            ("db.host", &self.db.host, self.db.port),
+            ("redis.host", &self.redis.host, self.redis.port),
+        ] {
+            if let Err(message) = resolve(host, port).await {
+                check(false, field, message);
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidConfig(problems))
+        }
+    }
+}
+
+async fn resolve(host: &str, port: u16) -> Result<(), String> {
+    if host.is_empty() {
+        return Err("must not be empty".into());
+    }
+
+    match lookup_host((host, port)).await {
+        Ok(addrs) => match addrs.count() {
+            0 => Err(format!("'{}' did not resolve to any address", host)),
+            _ => Ok(()),
+        },
+        Err(err) => Err(format!("'{}' could not be resolved: {}", host, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File};
+
+    use super::*;
+
+    fn defaults() -> AppConfig {
+        let defaults = concat!(env!("CARGO_MANIFEST_DIR"), "/src/config/env/defaults.yml");
+
+        Config::builder()
+            .add_source(File::with_name(defaults))
+            .build()
+            .and_then(|conf| conf.try_deserialize())
+            .unwrap()
+    }
+
+    async fn problems(conf: &AppConfig) -> Vec<&'static str> {
+        match conf.validate().await {
+            Ok(()) => Vec::new(),
+            Err(InvalidConfig(problems)) => problems.iter().map(|p| p.field).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn defaults_are_valid() {
+        assert_eq!(problems(&defaults()).await, Vec::<&str>::new());
+    }
+
+    #[tokio::test]
+    async fn reports_every_problem_at_once() {
+        let mut conf = defaults();
+        conf.db.max_connections = 0;
+        conf.rate_limit.backend = "disk".into();
+        conf.metrics.buckets = vec![0.1, 0.05];
+        conf.redis.host = String::new();
+
+        assert_eq!(
+            problems(&conf).await,
+            vec![
+                "db.max_connections",
+                "metrics.buckets",
+                "rate_limit.backend",
+                "redis.host"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn checks_the_concurrency_bounds() {
+        let mut conf = defaults();
+        conf.load_shedding.concurrency.enabled = true;
+        conf.load_shedding.concurrency.min = 10;
+        conf.load_shedding.concurrency.initial = 5;
+
+        assert_eq!(problems(&conf).await, vec!["load_shedding.concurrency"]);
+    }
+
+    #[tokio::test]
+    async fn needs_credentials_when_auth_is_enabled() {
+        let mut conf = defaults();
+        conf.auth.enabled = true;
+        conf.auth.api_keys.clear();
+        conf.auth.jwt_secret = None;
+
+        assert_eq!(problems(&conf).await, vec!["auth"]);
+
+        conf.auth.jwt_secret = Some(String::new());
+        assert_eq!(problems(&conf).await, vec!["auth.jwt_secret"]);
+    }
+}
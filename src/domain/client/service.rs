@@ -1,7 +1,6 @@
 use std::sync::Arc;
 
 use anyhow::Context;
-use axum::http::StatusCode;
 use derive_new::new;
 use libsql::de;
 use sea_query::{Expr, Query, SqliteQueryBuilder};
@@ -9,19 +8,33 @@ use sea_query::{Expr, Query, SqliteQueryBuilder};
 use crate::domain::client::model::{Client, ClientTable};
 use crate::tools::db::Database;
 use crate::tools::error::{CustomError, DomainError};
+use crate::tools::problem::ErrorCode;
+use crate::tools::retry::RetryPolicy;
 
 #[derive(new)]
 pub struct ClientService {
     db: Arc<dyn Database>,
+    retry: RetryPolicy,
 }
 
 impl ClientService {
+    /// retried on transient errors, unless it runs within a transaction (`conn`)
     pub async fn find(&self, id: u32, conn: Option<&dyn Database>) -> Result<Client, CustomError> {
-        let db = conn.unwrap_or(&*self.db);
+        match conn {
+            Some(conn) => Self::find_with(id, conn).await,
+            None => {
+                self.retry
+                    .run("find_client", || Self::find_with(id, &*self.db))
+                    .await
+            }
+        }
+    }
+
+    async fn find_with(id: u32, db: &dyn Database) -> Result<Client, CustomError> {
         let query = Self::find_query(id);
 
         let res = db
-            .query(&query)
+            .query("find_client", &query)
             .await
             .context("failed to query for rows")?
             .next()
@@ -33,10 +46,47 @@ impl ClientService {
             return Ok(meta);
         }
 
-        Err(DomainError::new(
+        Err(Self::not_found(id))?
+    }
+
+    pub async fn find_many(
+        &self,
+        ids: &[u32],
+        conn: Option<&dyn Database>,
+    ) -> Result<Vec<Client>, CustomError> {
+        match conn {
+            Some(conn) => Self::find_many_with(ids, conn).await,
+            None => {
+                self.retry
+                    .run("find_clients", || Self::find_many_with(ids, &*self.db))
+                    .await
+            }
+        }
+    }
+
+    async fn find_many_with(ids: &[u32], db: &dyn Database) -> Result<Vec<Client>, CustomError> {
+        let query = Self::find_many_query(ids);
+
+        let mut rows = db
+            .query("find_clients", &query)
+            .await
+            .context("failed to query for rows")?;
+
+        let mut clients = Vec::with_capacity(ids.len());
+        while let Some(row) = rows.next().await.context("failed to retrieve next row")? {
+            let client = de::from_row::<Client>(&row).context("failed to parse row")?;
+            clients.push(client);
+        }
+
+        Ok(clients)
+    }
+
+    pub fn not_found(id: u32) -> DomainError {
+        DomainError::new(
+            ErrorCode::ClientNotFound,
             format!("No matching client meta for client id {}", id),
-            StatusCode::NOT_FOUND.as_u16(),
-        ))?
+        )
+        .with_param("client_id", id)
     }
 
     fn find_query(client_id: u32) -> String {
@@ -52,6 +102,19 @@ impl ClientService {
             .to_owned()
     }
 
+    fn find_many_query(client_ids: &[u32]) -> String {
+        Query::select()
+            .columns([
+                ClientTable::ID,
+                ClientTable::Balance,
+                ClientTable::NegativeLimit,
+            ])
+            .from(ClientTable::Table)
+            .and_where(Expr::col(ClientTable::ID).is_in(client_ids.iter().copied()))
+            .to_string(SqliteQueryBuilder)
+            .to_owned()
+    }
+
     pub fn balance_update_query(client_id: u32, balance: i32) -> String {
         Query::update()
             .table(ClientTable::Table)
@@ -1,13 +1,28 @@
 use std::sync::Arc;
 
 use axum::extract::State;
-use axum::Json;
 
-use crate::domain::statement::model::Statement;
+use crate::domain::statement::model::{Statement, StatementV1};
 use crate::domain::statement::service::StatementService;
-use crate::tools::axum::Path;
+use crate::tools::axum::{Json, Path};
 use crate::tools::error::CustomError;
 
+#[utoipa::path(
+    get,
+    path = "/clientes/{client_id}/extrato",
+    tag = "extrato",
+    params(("client_id" = u32, Path, description = "client id")),
+    security(("api_key" = []), ("jwt" = [])),
+    responses(
+        (status = 200, body = Statement),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 401, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+        (status = 429, body = Problem, content_type = "application/problem+json"),
+        (status = 503, body = Problem, content_type = "application/problem+json"),
+    )
+)]
 #[tracing::instrument(skip_all, fields(client_id = % client_id))]
 pub async fn find_statement(
     State(statement_service): State<Arc<StatementService>>,
@@ -17,3 +32,29 @@ pub async fn find_statement(
 
     Ok(Json(response))
 }
+
+#[utoipa::path(
+    get,
+    path = "/v1/clients/{client_id}/statement",
+    tag = "v1",
+    params(("client_id" = u32, Path, description = "client id")),
+    security(("api_key" = []), ("jwt" = [])),
+    responses(
+        (status = 200, body = StatementV1),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 401, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+        (status = 429, body = Problem, content_type = "application/problem+json"),
+        (status = 503, body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[tracing::instrument(skip_all, fields(client_id = % client_id))]
+pub async fn find_statement_v1(
+    State(statement_service): State<Arc<StatementService>>,
+    Path(client_id): Path<u32>,
+) -> Result<Json<StatementV1>, CustomError> {
+    let response = statement_service.find(client_id).await?;
+
+    Ok(Json(response.into()))
+}
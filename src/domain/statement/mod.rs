@@ -1,3 +1,3 @@
 pub mod api;
-mod model;
+pub mod model;
 pub mod service;
@@ -1,8 +1,9 @@
 use derive_new::new;
 use serde::Serialize;
 use time::OffsetDateTime;
+use utoipa::ToSchema;
 
-#[derive(Debug, Serialize, Clone, new)]
+#[derive(Debug, Serialize, Clone, new, ToSchema)]
 pub struct Statement {
     #[serde(rename = "saldo")]
     pub balance: StatementBalance,
@@ -11,7 +12,7 @@ pub struct Statement {
     pub transactions: Vec<StatementTransaction>,
 }
 
-#[derive(Debug, Serialize, Clone, new)]
+#[derive(Debug, Serialize, Clone, new, ToSchema)]
 pub struct StatementBalance {
     #[serde(rename = "total")]
     pub balance: i32,
@@ -23,12 +24,13 @@ pub struct StatementBalance {
     pub requested_at: OffsetDateTime,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, ToSchema)]
 pub struct StatementTransaction {
     #[serde(rename = "valor")]
     pub amount: i32,
 
     #[serde(rename = "tipo")]
+    #[schema(pattern = "^[cd]$")]
     pub operation: String,
 
     #[serde(rename = "descricao")]
@@ -37,3 +39,55 @@ pub struct StatementTransaction {
     #[serde(rename = "realizada_em", with = "time::serde::rfc3339")]
     pub created_at: OffsetDateTime,
 }
+
+/// english counterpart of [`Statement`], served under `/v1`
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct StatementV1 {
+    pub balance: StatementBalanceV1,
+    pub last_transactions: Vec<StatementTransactionV1>,
+}
+
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct StatementBalanceV1 {
+    pub total: i32,
+    pub limit: i32,
+
+    #[serde(with = "time::serde::rfc3339")]
+    pub statement_date: OffsetDateTime,
+}
+
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct StatementTransactionV1 {
+    pub amount: i32,
+
+    #[serde(rename = "type")]
+    #[schema(pattern = "^[cd]$")]
+    pub operation: String,
+
+    pub description: String,
+
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+impl From<Statement> for StatementV1 {
+    fn from(val: Statement) -> Self {
+        StatementV1 {
+            balance: StatementBalanceV1 {
+                total: val.balance.balance,
+                limit: val.balance.negative_limit,
+                statement_date: val.balance.requested_at,
+            },
+            last_transactions: val
+                .transactions
+                .into_iter()
+                .map(|t| StatementTransactionV1 {
+                    amount: t.amount,
+                    operation: t.operation,
+                    description: t.description,
+                    created_at: t.created_at,
+                })
+                .collect(),
+        }
+    }
+}
@@ -9,28 +9,47 @@ use crate::domain::client::service::ClientService;
 use crate::domain::statement::model::{Statement, StatementBalance, StatementTransaction};
 use crate::domain::transaction::model::Transaction;
 use crate::domain::transaction::service::TransactionService;
-use crate::tools::db::Database;
+use crate::tools::db::{Database, LibsqlTransaction};
 use crate::tools::error::CustomError;
+use crate::tools::retry::RetryPolicy;
 
 #[derive(new)]
 pub struct StatementService {
     client_service: Arc<ClientService>,
     transaction_service: Arc<TransactionService>,
     db: Arc<dyn Database>,
+    retry: RetryPolicy,
 }
 
 impl StatementService {
+    /// the whole read-only transaction is retried on transient errors
     pub async fn find(&self, client_id: u32) -> Result<Statement, CustomError> {
+        self.retry
+            .run("find_statement", || self.find_once(client_id))
+            .await
+    }
+
+    async fn find_once(&self, client_id: u32) -> Result<Statement, CustomError> {
         let tx = self
             .db
             .transaction(ReadOnly)
             .await
             .context("failed to start a transaction")?;
 
-        let client = self.client_service.find(client_id, Some(&tx)).await?;
+        let statement = self.find_with(client_id, &tx).await;
+
+        tx.finish(statement, "failed to commit transaction").await
+    }
+
+    async fn find_with(
+        &self,
+        client_id: u32,
+        tx: &LibsqlTransaction,
+    ) -> Result<Statement, CustomError> {
+        let client = self.client_service.find(client_id, Some(tx)).await?;
         let transactions = self
             .transaction_service
-            .find_latest(client_id, Some(&tx))
+            .find_latest(client_id, Some(tx))
             .await?;
 
         let balance = StatementBalance::new(
@@ -43,8 +62,6 @@ impl StatementService {
             .map(|t| t.into())
             .collect::<Vec<_>>();
 
-        tx.commit().await.context("failed to commit transaction")?;
-
         Ok(Statement::new(balance, statement_transactions))
     }
 }
@@ -1,23 +1,48 @@
 use std::sync::Arc;
 
 use axum::extract::State;
-use axum::Json;
 use validify::Validate;
 
 use crate::domain::transaction::model::{
-    CreateTransactionPayload, CreateTransactionRequest, CreateTransactionResponse,
+    CreateTransactionPayload, CreateTransactionPayloadV1, CreateTransactionRequest,
+    CreateTransactionResponse, CreateTransactionResponseV1,
 };
 use crate::domain::transaction::service::TransactionService;
-use crate::tools::axum::Path;
+use crate::tools::axum::{Json, Path};
 use crate::tools::error::CustomError;
+use crate::tools::metrics::TRANSACTION_REJECTIONS_COUNTER;
 
+#[utoipa::path(
+    post,
+    path = "/clientes/{client_id}/transacoes",
+    tag = "transacoes",
+    params(("client_id" = u32, Path, description = "client id")),
+    security(("api_key" = []), ("jwt" = [])),
+    request_body = CreateTransactionPayload,
+    responses(
+        (status = 200, body = CreateTransactionResponse),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 401, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+        (status = 415, body = Problem, content_type = "application/problem+json"),
+        (status = 422, body = Problem, content_type = "application/problem+json"),
+        (status = 429, body = Problem, content_type = "application/problem+json"),
+        (status = 503, body = Problem, content_type = "application/problem+json"),
+    )
+)]
 #[tracing::instrument(skip_all, fields(client_id = % client_id))]
 pub async fn create_transaction(
     State(transaction_service): State<Arc<TransactionService>>,
     Path(client_id): Path<u32>,
     Json(payload): Json<CreateTransactionPayload>,
 ) -> Result<Json<CreateTransactionResponse>, CustomError> {
-    payload.validate()?;
+    if let Err(err) = payload.validate() {
+        TRANSACTION_REJECTIONS_COUNTER
+            .with_label_values(&["validation_failed"])
+            .inc();
+        return Err(err.into());
+    }
 
     let request = CreateTransactionRequest::new(client_id, payload);
     let response = transaction_service.create_transaction(request).await?;
@@ -26,3 +51,43 @@ pub async fn create_transaction(
 
     Ok(Json(response))
 }
+
+#[utoipa::path(
+    post,
+    path = "/v1/clients/{client_id}/transactions",
+    tag = "v1",
+    params(("client_id" = u32, Path, description = "client id")),
+    security(("api_key" = []), ("jwt" = [])),
+    request_body = CreateTransactionPayloadV1,
+    responses(
+        (status = 200, body = CreateTransactionResponseV1),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 401, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+        (status = 415, body = Problem, content_type = "application/problem+json"),
+        (status = 422, body = Problem, content_type = "application/problem+json"),
+        (status = 429, body = Problem, content_type = "application/problem+json"),
+        (status = 503, body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[tracing::instrument(skip_all, fields(client_id = % client_id))]
+pub async fn create_transaction_v1(
+    State(transaction_service): State<Arc<TransactionService>>,
+    Path(client_id): Path<u32>,
+    Json(payload): Json<CreateTransactionPayloadV1>,
+) -> Result<Json<CreateTransactionResponseV1>, CustomError> {
+    if let Err(err) = payload.validate() {
+        TRANSACTION_REJECTIONS_COUNTER
+            .with_label_values(&["validation_failed"])
+            .inc();
+        return Err(err.into());
+    }
+
+    let request = CreateTransactionRequest::new(client_id, payload.into());
+    let response = transaction_service.create_transaction(request).await?;
+
+    tracing::info!("Transaction created successfully");
+
+    Ok(Json(response.into()))
+}
@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use libsql::TransactionBehavior::Immediate;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout_at, Instant};
+use tracing::Instrument;
+
+use crate::config::app_config::AppConfig;
+use crate::domain::client::model::Client;
+use crate::domain::client::service::ClientService;
+use crate::domain::transaction::model::{CreateTransactionRequest, CreateTransactionResponse};
+use crate::domain::transaction::service::TransactionService;
+use crate::tools::db::Database;
+use crate::tools::error::CustomError;
+use crate::tools::metrics::{DeferredObserve, OPS_HISTOGRAM, TRANSACTIONS_COUNTER};
+use crate::tools::overload;
+use crate::tools::request_id;
+use crate::tools::retry::RetryPolicy;
+
+type Outcome = Result<CreateTransactionResponse, CustomError>;
+
+/// Group commit writer: pending transactions are collected for a short window and persisted
+/// together in a single write transaction, each caller being answered once the batch commits.
+pub struct TransactionBatcher {
+    sender: mpsc::Sender<PendingTransaction>,
+}
+
+struct PendingTransaction {
+    request: CreateTransactionRequest,
+    // the worker doesn't run within the request task, so the id is carried along
+    request_id: Option<String>,
+    reply: oneshot::Sender<Outcome>,
+}
+
+struct BatchWorker {
+    client_service: Arc<ClientService>,
+    db: Arc<dyn Database>,
+    max_size: usize,
+    window: Duration,
+    retry: RetryPolicy,
+}
+
+impl TransactionBatcher {
+    pub fn new(
+        conf: &AppConfig,
+        client_service: Arc<ClientService>,
+        db: Arc<dyn Database>,
+    ) -> Self {
+        let max_size = conf.batching.max_size.max(1);
+        let (sender, receiver) = mpsc::channel(max_size * 2);
+
+        let worker = BatchWorker {
+            client_service,
+            db,
+            max_size,
+            window: conf.batching.window,
+            retry: RetryPolicy::new(&conf.db.retry),
+        };
+        tokio::spawn(worker.run(receiver));
+
+        Self { sender }
+    }
+
+    pub async fn submit(&self, request: CreateTransactionRequest) -> Outcome {
+        let (reply, response) = oneshot::channel();
+
+        // only getting into a batch is bounded by the deadline, a submitted request is answered
+        // once its batch commits
+        let send = self.sender.send(PendingTransaction {
+            request,
+            request_id: request_id::current(),
+            reply,
+        });
+        overload::within_deadline(send)
+            .await?
+            .map_err(|_| anyhow::anyhow!("transaction batcher is not running"))?;
+
+        response
+            .await
+            .context("transaction batcher dropped the request")?
+    }
+}
+
+impl BatchWorker {
+    async fn run(self, mut receiver: mpsc::Receiver<PendingTransaction>) {
+        while let Some(first) = receiver.recv().await {
+            let deadline = Instant::now() + self.window;
+            let mut batch = vec![first];
+
+            while batch.len() < self.max_size {
+                match timeout_at(deadline, receiver.recv()).await {
+                    Ok(Some(pending)) => batch.push(pending),
+                    _ => break,
+                }
+            }
+
+            self.process(batch).await;
+        }
+    }
+
+    async fn process(&self, batch: Vec<PendingTransaction>) {
+        let request_ids = batch
+            .iter()
+            .filter_map(|pending| pending.request_id.as_deref())
+            .collect::<Vec<_>>()
+            .join(",");
+        let span = tracing::info_span!("commit_batch", request_ids = %request_ids);
+
+        self.process_batch(batch).instrument(span).await;
+    }
+
+    async fn process_batch(&self, batch: Vec<PendingTransaction>) {
+        let _deferred_observe = DeferredObserve::new(&OPS_HISTOGRAM, &["commit_batch"]);
+        tracing::debug!("Committing batch of {} transactions", batch.len());
+
+        let mut requests = Vec::with_capacity(batch.len());
+        let mut request_ids = Vec::with_capacity(batch.len());
+        let mut replies = Vec::with_capacity(batch.len());
+        for pending in batch {
+            requests.push(pending.request);
+            request_ids.push(pending.request_id);
+            replies.push(pending.reply);
+        }
+
+        // the whole write transaction is replayed on transient errors
+        let persist = || self.persist(requests.clone(), &request_ids);
+        match self.retry.run("persist_transaction_batch", persist).await {
+            Ok(outcomes) => {
+                for (reply, outcome) in replies.into_iter().zip(outcomes) {
+                    // the caller may have gone away in the meantime, nothing to do about it
+                    let _ = reply.send(outcome);
+                }
+            }
+
+            Err(err) => {
+                let overloaded = matches!(err, CustomError::Unavailable(_));
+                let message = err.to_string();
+                for reply in replies {
+                    let err = if overloaded {
+                        CustomError::Unavailable(message.clone())
+                    } else {
+                        CustomError::Unexpected(anyhow::anyhow!(
+                            "failed to commit transaction batch: {}",
+                            message
+                        ))
+                    };
+                    let _ = reply.send(Err(err));
+                }
+            }
+        }
+    }
+
+    /// validates every request sequentially against the in-memory balance of its client,
+    /// then writes the accepted ones (and the final balances) within one write transaction
+    async fn persist(
+        &self,
+        requests: Vec<CreateTransactionRequest>,
+        request_ids: &[Option<String>],
+    ) -> Result<Vec<Outcome>, CustomError> {
+        let tx = self
+            .db
+            .transaction(Immediate)
+            .await
+            .context("failed to start a transaction")?;
+
+        let persisted = self.persist_with(requests, request_ids, &tx).await;
+        let (outcomes, operations) = tx
+            .finish(persisted, "failed to commit transaction batch")
+            .await?;
+
+        for operation in operations {
+            TRANSACTIONS_COUNTER.with_label_values(&[operation]).inc();
+        }
+
+        Ok(outcomes)
+    }
+
+    /// the outcome of each request, and the operations of the accepted ones
+    async fn persist_with(
+        &self,
+        requests: Vec<CreateTransactionRequest>,
+        request_ids: &[Option<String>],
+        tx: &dyn Database,
+    ) -> Result<(Vec<Outcome>, Vec<&'static str>), CustomError> {
+        let mut client_ids = requests.iter().map(|r| r.client_id).collect::<Vec<_>>();
+        client_ids.sort_unstable();
+        client_ids.dedup();
+
+        let clients = self.client_service.find_many(&client_ids, Some(tx)).await?;
+
+        Self::write_batch(clients, requests, request_ids, tx).await
+    }
+
+    /// applies the requests in order to the balances of `clients`, then writes the accepted
+    /// ones along with one balance update per touched client. Each insert is tagged with the id
+    /// of the request it comes from.
+    async fn write_batch(
+        clients: Vec<Client>,
+        requests: Vec<CreateTransactionRequest>,
+        request_ids: &[Option<String>],
+        tx: &dyn Database,
+    ) -> Result<(Vec<Outcome>, Vec<&'static str>), CustomError> {
+        let mut clients = clients
+            .into_iter()
+            .map(|client| (client.id, client))
+            .collect::<HashMap<_, _>>();
+
+        let mut touched = Vec::new();
+        let mut statements = Vec::new();
+        let mut operations = Vec::new();
+
+        let outcomes = requests
+            .into_iter()
+            .zip(request_ids)
+            .map(|(request, request_id)| -> Outcome {
+                let client = clients
+                    .get_mut(&request.client_id)
+                    .ok_or_else(|| ClientService::not_found(request.client_id))?;
+                let new_balance = TransactionService::apply(client, &request)?;
+
+                client.balance = new_balance;
+                if !touched.contains(&client.id) {
+                    touched.push(client.id);
+                }
+                operations.push(request.payload.operation_name());
+                let insert = TransactionService::insert_query(request);
+                let tagged = request_id::tag_sql_with(request_id.as_deref(), &insert);
+                statements.push(tagged.into_owned());
+
+                Ok(CreateTransactionResponse::new(
+                    client.negative_limit,
+                    new_balance,
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        if !statements.is_empty() {
+            statements.extend(
+                touched
+                    .into_iter()
+                    .map(|id| ClientService::balance_update_query(id, clients[&id].balance)),
+            );
+
+            tx.execute_batch("persist_transaction_batch", &statements.join(";"))
+                .await
+                .context("failed to persist transaction batch")?;
+        }
+
+        Ok((outcomes, operations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use axum::async_trait;
+    use libsql::{Rows, TransactionBehavior};
+
+    use super::*;
+    use crate::domain::transaction::model::{
+        CreateTransactionPayload, OPERATION_CREDIT, OPERATION_DEBIT,
+    };
+    use crate::tools::db::{DbResult, LibsqlTransaction};
+    use crate::tools::problem::ErrorCode;
+
+    /// records the batches written, the clients are handed to `write_batch` directly
+    #[derive(Default)]
+    struct FakeDatabase {
+        batches: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Database for FakeDatabase {
+        async fn execute_batch(&self, _operation: &'static str, sql: &str) -> DbResult<()> {
+            self.batches.lock().unwrap().push(sql.to_string());
+            Ok(())
+        }
+
+        async fn query(&self, _operation: &'static str, _sql: &str) -> DbResult<Rows> {
+            unreachable!("writing a batch runs no query")
+        }
+
+        async fn transaction(&self, _behavior: TransactionBehavior) -> DbResult<LibsqlTransaction> {
+            unreachable!("writing a batch starts no transaction")
+        }
+    }
+
+    fn client(id: u32, balance: i32, negative_limit: i32) -> Client {
+        Client {
+            id,
+            negative_limit,
+            balance,
+        }
+    }
+
+    fn request(client_id: u32, operation: &str, amount: i32) -> CreateTransactionRequest {
+        let payload = CreateTransactionPayload {
+            amount,
+            operation: operation.to_string(),
+            description: "test".to_string(),
+        };
+
+        CreateTransactionRequest::new(client_id, payload)
+    }
+
+    async fn write(
+        db: &FakeDatabase,
+        clients: Vec<Client>,
+        requests: Vec<CreateTransactionRequest>,
+    ) -> (Vec<Outcome>, Vec<&'static str>) {
+        let request_ids = vec![None; requests.len()];
+
+        BatchWorker::write_batch(clients, requests, &request_ids, db)
+            .await
+            .unwrap()
+    }
+
+    fn balance(outcome: &Outcome) -> i32 {
+        outcome.as_ref().unwrap().balance
+    }
+
+    fn failed_with(outcome: &Outcome, code: ErrorCode) -> bool {
+        matches!(outcome, Err(CustomError::Domain(err)) if err.code == code)
+    }
+
+    #[tokio::test]
+    async fn balances_follow_each_other_for_one_client() {
+        let db = FakeDatabase::default();
+        let requests = vec![
+            request(1, OPERATION_CREDIT, 100),
+            request(1, OPERATION_DEBIT, 30),
+            request(1, OPERATION_DEBIT, 50),
+        ];
+
+        let (outcomes, operations) = write(&db, vec![client(1, 0, 0)], requests).await;
+
+        let balances = outcomes.iter().map(balance).collect::<Vec<_>>();
+        assert_eq!(balances, [100, 70, 20]);
+        assert_eq!(operations, ["credit", "debit", "debit"]);
+    }
+
+    #[tokio::test]
+    async fn insufficient_funds_leave_the_neighbours_alone() {
+        let db = FakeDatabase::default();
+        let requests = vec![
+            request(1, OPERATION_DEBIT, 40),
+            request(1, OPERATION_DEBIT, 100),
+            request(1, OPERATION_DEBIT, 50),
+        ];
+
+        let (outcomes, operations) = write(&db, vec![client(1, 0, 100)], requests).await;
+
+        assert_eq!(balance(&outcomes[0]), -40);
+        assert!(failed_with(&outcomes[1], ErrorCode::InsufficientFunds));
+        assert_eq!(balance(&outcomes[2]), -90);
+        assert_eq!(operations, ["debit", "debit"]);
+    }
+
+    #[tokio::test]
+    async fn unknown_clients_are_not_found() {
+        let db = FakeDatabase::default();
+        let requests = vec![
+            request(2, OPERATION_CREDIT, 10),
+            request(1, OPERATION_CREDIT, 10),
+        ];
+
+        let (outcomes, _) = write(&db, vec![client(1, 0, 0)], requests).await;
+
+        assert!(failed_with(&outcomes[0], ErrorCode::ClientNotFound));
+        assert_eq!(balance(&outcomes[1]), 10);
+    }
+
+    #[tokio::test]
+    async fn touched_clients_get_one_balance_update() {
+        let db = FakeDatabase::default();
+        let clients = vec![client(1, 0, 0), client(2, 0, 0), client(3, 0, 0)];
+        let requests = vec![
+            request(1, OPERATION_CREDIT, 10),
+            request(2, OPERATION_CREDIT, 20),
+            request(1, OPERATION_CREDIT, 30),
+            // rejected, so client 3 is not touched
+            request(3, OPERATION_DEBIT, 1),
+        ];
+
+        write(&db, clients, requests).await;
+
+        let batches = db.batches.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+        let statements = batches[0].split(';').collect::<Vec<_>>();
+        let updates = statements
+            .iter()
+            .filter(|statement| statement.starts_with("UPDATE"))
+            .collect::<Vec<_>>();
+        assert_eq!(statements.len(), 5);
+        assert_eq!(
+            updates,
+            [
+                &ClientService::balance_update_query(1, 40),
+                &ClientService::balance_update_query(2, 20),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn nothing_is_written_when_every_request_is_rejected() {
+        let db = FakeDatabase::default();
+
+        let (outcomes, _) = write(&db, vec![], vec![request(1, OPERATION_CREDIT, 10)]).await;
+
+        assert!(failed_with(&outcomes[0], ErrorCode::ClientNotFound));
+        assert!(db.batches.lock().unwrap().is_empty());
+    }
+}
@@ -1,3 +1,4 @@
 pub mod api;
+pub mod batcher;
 pub mod model;
 pub mod service;
@@ -3,6 +3,7 @@ use derive_new::new;
 use sea_query::Iden;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
+use utoipa::ToSchema;
 use validify::Validate;
 
 pub const OPERATION_CREDIT: &str = "c";
@@ -20,28 +21,69 @@ pub struct Transaction {
     pub created_at: OffsetDateTime,
 }
 
-#[derive(Debug, Deserialize, Validate, Clone)]
+#[derive(Debug, Deserialize, Validate, Clone, ToSchema)]
 pub struct CreateTransactionPayload {
     #[serde(rename = "valor")]
     #[validate(range(min = 1.))]
+    #[schema(minimum = 1)]
     pub amount: i32,
 
     #[serde(rename = "tipo")]
     #[validate(is_in(OPERATIONS))]
+    #[schema(pattern = "^[cd]$")]
     pub operation: String,
 
     #[serde(rename = "descricao")]
     #[validate(length(min = 1, max = 10))]
+    #[schema(min_length = 1, max_length = 10)]
     pub description: String,
 }
 
+/// english counterpart of [`CreateTransactionPayload`], served under `/v1`
+#[derive(Debug, Deserialize, Validate, Clone, ToSchema)]
+pub struct CreateTransactionPayloadV1 {
+    #[validate(range(min = 1.))]
+    #[schema(minimum = 1)]
+    pub amount: i32,
+
+    #[serde(rename = "type")]
+    #[validate(is_in(OPERATIONS))]
+    #[schema(pattern = "^[cd]$")]
+    pub operation: String,
+
+    #[validate(length(min = 1, max = 10))]
+    #[schema(min_length = 1, max_length = 10)]
+    pub description: String,
+}
+
+impl CreateTransactionPayload {
+    /// metric label of the operation
+    pub fn operation_name(&self) -> &'static str {
+        if self.operation == OPERATION_CREDIT {
+            "credit"
+        } else {
+            "debit"
+        }
+    }
+}
+
+impl From<CreateTransactionPayloadV1> for CreateTransactionPayload {
+    fn from(val: CreateTransactionPayloadV1) -> Self {
+        CreateTransactionPayload {
+            amount: val.amount,
+            operation: val.operation,
+            description: val.description,
+        }
+    }
+}
+
 #[derive(Debug, Clone, new)]
 pub struct CreateTransactionRequest {
     pub client_id: u32,
     pub payload: CreateTransactionPayload,
 }
 
-#[derive(Debug, Serialize, new)]
+#[derive(Debug, Serialize, new, ToSchema)]
 pub struct CreateTransactionResponse {
     #[serde(rename = "limite")]
     pub negative_limit: i32,
@@ -50,6 +92,22 @@ pub struct CreateTransactionResponse {
     pub balance: i32,
 }
 
+/// english counterpart of [`CreateTransactionResponse`], served under `/v1`
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreateTransactionResponseV1 {
+    pub limit: i32,
+    pub balance: i32,
+}
+
+impl From<CreateTransactionResponse> for CreateTransactionResponseV1 {
+    fn from(val: CreateTransactionResponse) -> Self {
+        CreateTransactionResponseV1 {
+            limit: val.negative_limit,
+            balance: val.balance,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Iden, PartialEq)]
 pub enum TransactionTable {
     #[iden = "transactions"]
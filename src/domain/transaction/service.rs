@@ -1,25 +1,36 @@
 use std::sync::Arc;
 
 use anyhow::Context;
-use axum::http::StatusCode;
 use derive_new::new;
 use libsql::de;
+use libsql::TransactionBehavior::Immediate;
 use sea_query::{Expr, Order, Query, SqliteQueryBuilder};
 
+use crate::domain::client::model::Client;
 use crate::domain::client::service::ClientService;
+use crate::domain::transaction::batcher::TransactionBatcher;
 use crate::domain::transaction::model::{
     CreateTransactionRequest, CreateTransactionResponse, Transaction, TransactionTable,
     OPERATION_CREDIT,
 };
-use crate::tools::db::Database;
+use crate::tools::db::{Database, LibsqlTransaction};
 use crate::tools::error::{CustomError, DomainError};
 use crate::tools::locker::Locker;
+use crate::tools::metrics::{
+    LOCK_FALLBACK_COUNTER, SHED_COUNTER, TRANSACTIONS_COUNTER, TRANSACTION_REJECTIONS_COUNTER,
+};
+use crate::tools::problem::ErrorCode;
+use crate::tools::retry::RetryPolicy;
 
 #[derive(new)]
 pub struct TransactionService {
     client_service: Arc<ClientService>,
     db: Arc<dyn Database>,
     locker: Arc<Locker>,
+    batcher: Option<Arc<TransactionBatcher>>,
+    /// see `redis.fallback`
+    fallback: bool,
+    retry: RetryPolicy,
 }
 
 impl TransactionService {
@@ -27,22 +38,59 @@ impl TransactionService {
         &self,
         request: CreateTransactionRequest,
     ) -> Result<CreateTransactionResponse, CustomError> {
-        let key = format!("transaction:{}", request.client_id);
+        if let Some(batcher) = &self.batcher {
+            // batched writes are serialized by the database write transaction instead of the lock
+            return batcher.submit(request).await;
+        }
 
-        self.locker
-            .with_lock(key, || self.process_new_transaction(request))
-            .await
+        let key = format!("transaction:{}", request.client_id);
+        let operation = request.payload.operation_name();
+        let unlocked_request = request.clone();
+
+        let response = self
+            .locker
+            .with_lock_or_else(
+                key,
+                |expires_at| async move {
+                    // replaying the transaction is only safe while the lock still covers it
+                    let process = || self.process_in_transaction(request.clone());
+                    self.retry
+                        .run_before("persist_transaction", expires_at, process)
+                        .await
+                },
+                |err| self.process_unlocked(unlocked_request, err),
+            )
+            .await?;
+        TRANSACTIONS_COUNTER.with_label_values(&[operation]).inc();
+
+        Ok(response)
     }
 
+    /// retried on transient errors, unless it runs within a transaction (`conn`)
     pub async fn find_latest(
         &self,
         client_id: u32,
         conn: Option<&dyn Database>,
     ) -> Result<Vec<Transaction>, CustomError> {
-        let db = conn.unwrap_or(&*self.db);
+        match conn {
+            Some(conn) => Self::find_latest_with(client_id, conn).await,
+            None => {
+                let find = || Self::find_latest_with(client_id, &*self.db);
+                self.retry.run("find_latest_transactions", find).await
+            }
+        }
+    }
+
+    async fn find_latest_with(
+        client_id: u32,
+        db: &dyn Database,
+    ) -> Result<Vec<Transaction>, CustomError> {
         let query = Self::find_latest_query(client_id);
 
-        let mut rows = db.query(&query).await.context("failed to query for rows")?;
+        let mut rows = db
+            .query("find_latest_transactions", &query)
+            .await
+            .context("failed to query for rows")?;
 
         let mut transactions = Vec::new();
         while let Some(row) = rows.next().await.context("failed to retrieve next row")? {
@@ -56,19 +104,15 @@ impl TransactionService {
     async fn process_new_transaction(
         &self,
         request: CreateTransactionRequest,
+        tx: &LibsqlTransaction,
     ) -> Result<CreateTransactionResponse, CustomError> {
-        let client_id = request.client_id;
-        let meta = self.client_service.find(client_id, None).await?;
-        let new_balance = Self::calculate_new_balance(meta.balance, &request);
+        let meta = self
+            .client_service
+            .find(request.client_id, Some(tx))
+            .await?;
+        let new_balance = Self::apply(&meta, &request)?;
 
-        if new_balance < -meta.negative_limit {
-            return Err(DomainError::new(
-                format!("Insufficient funds for client {}", client_id),
-                StatusCode::UNPROCESSABLE_ENTITY.as_u16(),
-            ))?;
-        }
-
-        self.persist(request, new_balance).await?;
+        self.persist(request, new_balance, tx).await?;
 
         Ok(CreateTransactionResponse::new(
             meta.negative_limit,
@@ -76,6 +120,69 @@ impl TransactionService {
         ))
     }
 
+    /// Degraded mode, used while redis can't be reached: the `IMMEDIATE` transaction the locked
+    /// path runs in already lets sqld serialize the writes, so requests that got the lock just
+    /// before redis went away can't race with these ones.
+    async fn process_unlocked(
+        &self,
+        request: CreateTransactionRequest,
+        err: CustomError,
+    ) -> Result<CreateTransactionResponse, CustomError> {
+        if !self.fallback {
+            if matches!(err, CustomError::Unavailable(_)) {
+                SHED_COUNTER.with_label_values(&["lock"]).inc();
+            }
+            return Err(err);
+        }
+
+        tracing::debug!("Locker unavailable, writing without the lock: {}", err);
+        LOCK_FALLBACK_COUNTER.inc();
+
+        // the whole transaction is replayed, reading the balance again
+        self.retry
+            .run("persist_transaction_unlocked", || {
+                self.process_in_transaction(request.clone())
+            })
+            .await
+    }
+
+    /// reads and updates the balance within an `IMMEDIATE` transaction, the database stays
+    /// consistent even when the lock is lost or expires while the request runs
+    async fn process_in_transaction(
+        &self,
+        request: CreateTransactionRequest,
+    ) -> Result<CreateTransactionResponse, CustomError> {
+        let tx = self
+            .db
+            .transaction(Immediate)
+            .await
+            .context("failed to start a transaction")?;
+
+        let response = self.process_new_transaction(request, &tx).await;
+
+        tx.finish(response, "failed to commit new transaction")
+            .await
+    }
+
+    /// calculates the balance resulting from the request, refusing it if the client limit is exceeded
+    pub fn apply(client: &Client, request: &CreateTransactionRequest) -> Result<i32, CustomError> {
+        let new_balance = Self::calculate_new_balance(client.balance, request);
+
+        if new_balance < -client.negative_limit {
+            TRANSACTION_REJECTIONS_COUNTER
+                .with_label_values(&["insufficient_funds"])
+                .inc();
+
+            return Err(DomainError::new(
+                ErrorCode::InsufficientFunds,
+                format!("Insufficient funds for client {}", client.id),
+            )
+            .with_param("client_id", client.id))?;
+        }
+
+        Ok(new_balance)
+    }
+
     fn calculate_new_balance(current_balance: i32, request: &CreateTransactionRequest) -> i32 {
         let amount = if request.payload.operation == OPERATION_CREDIT {
             request.payload.amount
@@ -90,21 +197,22 @@ impl TransactionService {
         &self,
         request: CreateTransactionRequest,
         new_balance: i32,
+        tx: &LibsqlTransaction,
     ) -> Result<(), CustomError> {
         let balance_update = ClientService::balance_update_query(request.client_id, new_balance);
         let transaction_insert = Self::insert_query(request);
 
-        let statements = [balance_update, transaction_insert].join(";");
-
-        self.db
-            .execute_batch(&statements)
-            .await
-            .context("failed to persist new transaction")?;
+        tx.execute_batch(
+            "persist_transaction",
+            &[balance_update, transaction_insert].join(";"),
+        )
+        .await
+        .context("failed to persist new transaction")?;
 
         Ok(())
     }
 
-    fn insert_query(request: CreateTransactionRequest) -> String {
+    pub fn insert_query(request: CreateTransactionRequest) -> String {
         Query::insert()
             .into_table(TransactionTable::Table)
             .columns([
@@ -1,26 +1,67 @@
 use std::net::SocketAddr;
+use std::process::ExitCode;
 
+use anyhow::Context;
+use clap::Parser;
 use tokio::net::TcpListener;
-use tracing_subscriber::fmt as log_guard;
 
+use crate::config::app_config::AppConfig;
+use crate::config::cli::Args;
+use crate::config::reload::ConfigReloader;
 use crate::state::State;
-use crate::tools::metrics;
+use crate::tools::{logging, telemetry};
 
 mod config;
 mod domain;
+mod openapi;
 mod routes;
 mod state;
 mod tools;
 
 #[tokio::main]
-async fn main() {
-    log_guard().init();
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            // printed as a plain message rather than a panic backtrace
+            eprintln!("error: {:#}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let config = AppConfig::new(&args).await?;
+    // the guard flushes file logs when `run` returns
+    let (log_handle, _log_guard) = logging::init(&config)?;
+
+    let state = State::new(config).await?;
 
-    let state = State::new().await;
-    metrics::register(&state.prometheus_registry);
+    ConfigReloader::new(
+        args,
+        state.db.clone(),
+        state.locker.clone(),
+        state.rate_limiter.clone(),
+        log_handle,
+    )
+    .spawn(&state.config);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], state.config.server.port));
-    let listener = TcpListener::bind(addr).await.unwrap();
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind to {}", addr))?;
+
+    let shutdown = state.shutdown.clone().wait(state.config.shutdown.drain);
+    axum::serve(listener, routes::new(state.clone()))
+        .with_graceful_shutdown(shutdown)
+        .await
+        .context("server stopped unexpectedly")?;
+
+    // every in-flight request has completed (and released its lock) at this point
+    state.db.close().await;
+    telemetry::shutdown().await;
+    tracing::info!("Shutdown complete");
 
-    axum::serve(listener, routes::new(state)).await.unwrap();
+    Ok(())
 }
@@ -0,0 +1,62 @@
+use axum::response::IntoResponse;
+use axum::Json;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use crate::domain::statement::{api as statement_api, model as statement_model};
+use crate::domain::transaction::{api as transaction_api, model as transaction_model};
+use crate::tools::auth::API_KEY_HEADER;
+use crate::tools::problem::Problem;
+
+/// OpenAPI 3 document of the public API, generated from the handlers and their models
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        transaction_api::create_transaction,
+        transaction_api::create_transaction_v1,
+        statement_api::find_statement,
+        statement_api::find_statement_v1,
+    ),
+    components(schemas(
+        transaction_model::CreateTransactionPayload,
+        transaction_model::CreateTransactionResponse,
+        transaction_model::CreateTransactionPayloadV1,
+        transaction_model::CreateTransactionResponseV1,
+        statement_model::Statement,
+        statement_model::StatementBalance,
+        statement_model::StatementTransaction,
+        statement_model::StatementV1,
+        statement_model::StatementBalanceV1,
+        statement_model::StatementTransactionV1,
+        Problem,
+    )),
+    modifiers(&SecuritySchemes)
+)]
+pub struct ApiDoc;
+
+/// the credentials accepted by [`crate::tools::auth::Authenticator`]
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))),
+        );
+        components.add_security_scheme(
+            "jwt",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
+
+pub async fn get() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
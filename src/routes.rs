@@ -1,20 +1,76 @@
+use axum::middleware;
 use axum::routing::{get, post, IntoMakeService};
 use axum::Router;
 use tower_http::catch_panic::CatchPanicLayer;
 
-use crate::domain::statement::api::find_statement;
-use crate::domain::transaction::api::create_transaction;
+use crate::domain::statement::api::{find_statement, find_statement_v1};
+use crate::domain::transaction::api::{create_transaction, create_transaction_v1};
+use crate::openapi;
 use crate::state::State;
 use crate::tools::error::handle_panic;
-use crate::tools::metrics;
+use crate::tools::{auth, health, metrics, overload, problem, rate_limit, request_id};
 
 pub(crate) fn new(state: State) -> IntoMakeService<Router> {
-    Router::new()
-        .route("/health", get(|| async { "OK" })) // used by haproxy
+    let deadlines = &state.config.load_shedding.deadlines;
+    let transaction_deadline =
+        middleware::from_fn_with_state(deadlines.transaction, overload::deadline);
+    let statement_deadline =
+        middleware::from_fn_with_state(deadlines.statement, overload::deadline);
+
+    // everything exposing client data goes through load shedding, authentication, rate limiting
+    // and the deadline of its route, in this order
+    let clients = Router::new()
+        .route(
+            "/clientes/:client_id/transacoes",
+            post(create_transaction).layer(transaction_deadline.clone()),
+        )
+        .route(
+            "/clientes/:client_id/extrato",
+            get(find_statement).layer(statement_deadline.clone()),
+        )
+        .route(
+            "/v1/clients/:client_id/transactions",
+            post(create_transaction_v1).layer(transaction_deadline),
+        )
+        .route(
+            "/v1/clients/:client_id/statement",
+            get(find_statement_v1).layer(statement_deadline),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authorize,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            overload::shed,
+        ));
+
+    let router = Router::new()
+        .route("/health", get(health::ready))
+        .route("/health/live", get(health::live))
+        .route("/health/ready", get(health::ready))
         .route("/prometheus", get(metrics::get))
-        .route("/clientes/:client_id/transacoes", post(create_transaction))
-        .route("/clientes/:client_id/extrato", get(find_statement))
+        .route("/openapi.json", get(openapi::get))
+        .merge(clients);
+
+    // profiles are only served to authenticated admins
+    #[cfg(feature = "diagnostics")]
+    let router = if state.config.auth.enabled {
+        router.merge(crate::tools::diagnostics::router(state.clone()))
+    } else {
+        tracing::warn!("Diagnostics routes are not mounted, they need auth.enabled");
+        router
+    };
+
+    router
+        .route_layer(middleware::from_fn(metrics::track))
         .with_state(state)
         .layer(CatchPanicLayer::custom(handle_panic))
+        .layer(middleware::from_fn(problem::enrich))
+        .layer(middleware::from_fn(request_id::propagate))
         .into_make_service()
 }
@@ -1,49 +1,104 @@
 use std::sync::Arc;
 
+use anyhow::Context;
 use axum::extract::FromRef;
 
 use crate::config::app_config::AppConfig;
 use crate::domain::client::service::ClientService;
 use crate::domain::statement::service::StatementService;
+use crate::domain::transaction::batcher::TransactionBatcher;
 use crate::domain::transaction::service::TransactionService;
+use crate::tools::auth::Authenticator;
 use crate::tools::db::PooledLibsqlDatabase;
+use crate::tools::health::Readiness;
 use crate::tools::locker::Locker;
+use crate::tools::metrics;
+use crate::tools::overload::ConcurrencyLimiter;
+use crate::tools::rate_limit::RateLimiter;
+use crate::tools::retry::RetryPolicy;
+use crate::tools::shutdown::Shutdown;
 
 #[derive(Clone, FromRef)]
 pub(crate) struct State {
     pub config: Arc<AppConfig>,
+    pub db: Arc<PooledLibsqlDatabase>,
     pub locker: Arc<Locker>,
     pub transaction_service: Arc<TransactionService>,
     pub statement_service: Arc<StatementService>,
     pub prometheus_registry: Arc<prometheus::Registry>,
+    pub shutdown: Arc<Shutdown>,
+    pub readiness: Arc<Readiness>,
+    pub authenticator: Arc<Authenticator>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub concurrency_limiter: Arc<ConcurrencyLimiter>,
 }
 
 impl State {
-    pub async fn new() -> Self {
-        let config = Arc::new(AppConfig::new());
-        let db = Arc::new(PooledLibsqlDatabase::new(&config).await);
-        let locker = Arc::new(Locker::new(&config).await);
+    pub async fn new(config: AppConfig) -> anyhow::Result<Self> {
+        let config = Arc::new(config);
+        // before anything that could observe a histogram
+        let prometheus_registry = Arc::new(prometheus::Registry::new());
+        metrics::register(&prometheus_registry, &config).context("failed to register metrics")?;
+
+        let db = Arc::new(
+            PooledLibsqlDatabase::new(&config)
+                .await
+                .context("failed to set up the database client")?,
+        );
+        let locker = Arc::new(
+            Locker::new(&config)
+                .await
+                .context("failed to set up the locker")?,
+        );
 
-        let client_service = Arc::new(ClientService::new(db.clone()));
+        let retry = RetryPolicy::new(&config.db.retry);
+        let client_service = Arc::new(ClientService::new(db.clone(), retry.clone()));
+        let batcher = config.batching.enabled.then(|| {
+            Arc::new(TransactionBatcher::new(
+                &config,
+                client_service.clone(),
+                db.clone(),
+            ))
+        });
         let transaction_service = Arc::new(TransactionService::new(
             client_service.clone(),
             db.clone(),
             locker.clone(),
+            batcher,
+            config.redis.fallback,
+            retry.clone(),
         ));
         let statement_service = Arc::new(StatementService::new(
             client_service,
             transaction_service.clone(),
-            db,
+            db.clone(),
+            retry,
         ));
 
-        let prometheus_registry = Arc::new(prometheus::Registry::new());
+        let shutdown = Arc::new(Shutdown::default());
+        let readiness = Arc::new(Readiness::new(
+            &config,
+            db.clone(),
+            locker.clone(),
+            shutdown.clone(),
+        ));
+        let authenticator = Arc::new(Authenticator::new(&config.auth));
+        let rate_limiter =
+            Arc::new(RateLimiter::new(&config).context("failed to set up the rate limiter")?);
+        let concurrency_limiter = Arc::new(ConcurrencyLimiter::new(&config));
 
-        State {
+        Ok(State {
             config,
+            db,
             locker,
             transaction_service,
             statement_service,
             prometheus_registry,
-        }
+            shutdown,
+            readiness,
+            authenticator,
+            rate_limiter,
+            concurrency_limiter,
+        })
     }
 }
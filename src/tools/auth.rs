@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use ring::constant_time;
+use ring::digest::{self, Digest, SHA256};
+use serde::Deserialize;
+
+use crate::config::app_config;
+use crate::tools::axum::Path;
+use crate::tools::error::{CustomError, DomainError};
+use crate::tools::problem::ErrorCode;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// only allowed to access its own client ids
+    #[default]
+    Client,
+    Admin,
+}
+
+/// the authenticated caller, available to handlers as a request extension
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+    pub role: Role,
+    pub clients: Vec<u32>,
+}
+
+impl Principal {
+    fn anonymous() -> Self {
+        Principal {
+            name: "anonymous".into(),
+            role: Role::Admin,
+            clients: Vec::new(),
+        }
+    }
+
+    pub fn can_access(&self, client_id: u32) -> bool {
+        self.role == Role::Admin || self.clients.contains(&client_id)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+
+    #[serde(default)]
+    role: Role,
+
+    #[serde(default)]
+    clients: Vec<u32>,
+}
+
+/// Resolves the caller from an `X-Api-Key` header or an HS256 signed `Authorization: Bearer` JWT
+pub struct Authenticator {
+    enabled: bool,
+    // keys are only kept as digests, see `api_key`
+    api_keys: Vec<(Digest, Principal)>,
+    jwt: Option<(DecodingKey, Validation)>,
+    // schemes sent back in `WWW-Authenticate` on 401
+    challenge: HeaderValue,
+}
+
+impl Authenticator {
+    pub fn new(conf: &app_config::Auth) -> Self {
+        let api_keys: Vec<_> = conf
+            .api_keys
+            .iter()
+            .map(|api_key| {
+                let principal = Principal {
+                    name: api_key.name.clone(),
+                    role: api_key.role,
+                    clients: api_key.clients.clone(),
+                };
+
+                (digest::digest(&SHA256, api_key.key.as_bytes()), principal)
+            })
+            .collect();
+
+        let jwt = conf.jwt_secret.as_ref().map(|secret| {
+            let mut validation = Validation::new(Algorithm::HS256);
+            if let Some(issuer) = &conf.jwt_issuer {
+                validation.set_issuer(&[issuer]);
+            }
+
+            (DecodingKey::from_secret(secret.as_bytes()), validation)
+        });
+
+        let challenge = match (api_keys.is_empty(), jwt.is_some()) {
+            (false, true) => r#"ApiKey header="X-Api-Key", Bearer"#,
+            (true, true) => "Bearer",
+            _ => r#"ApiKey header="X-Api-Key""#,
+        };
+
+        Self {
+            enabled: conf.enabled,
+            api_keys,
+            jwt,
+            challenge: HeaderValue::from_static(challenge),
+        }
+    }
+
+    /// Compares digests in constant time and goes through every key, so that the time taken
+    /// tells nothing about how close the given key is to a configured one.
+    fn api_key(&self, key: &[u8]) -> Option<&Principal> {
+        let given = digest::digest(&SHA256, key);
+
+        self.api_keys
+            .iter()
+            .fold(None, |found, (expected, principal)| {
+                let equal =
+                    constant_time::verify_slices_are_equal(expected.as_ref(), given.as_ref());
+                found.or(equal.is_ok().then_some(principal))
+            })
+    }
+
+    fn principal(&self, headers: &HeaderMap) -> Result<Principal, CustomError> {
+        if self.enabled {
+            self.authenticate(headers)
+        } else {
+            Ok(Principal::anonymous())
+        }
+    }
+
+    /// the 401 for `err`, along with the accepted schemes
+    fn challenged(&self, err: CustomError) -> Response {
+        let mut response = err.into_response();
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, self.challenge.clone());
+
+        response
+    }
+
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, CustomError> {
+        if let Some(key) = headers.get(API_KEY_HEADER) {
+            return self
+                .api_key(key.as_bytes())
+                .cloned()
+                .ok_or_else(|| unauthenticated("unknown api key"));
+        }
+
+        let bearer = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        if let Some(token) = bearer {
+            let Some((key, validation)) = &self.jwt else {
+                return Err(unauthenticated("jwt authentication is not configured"));
+            };
+
+            let claims = jsonwebtoken::decode::<Claims>(token.trim(), key, validation)
+                .map_err(|err| unauthenticated(&format!("invalid token: {}", err)))?
+                .claims;
+
+            return Ok(Principal {
+                name: claims.sub,
+                role: claims.role,
+                clients: claims.clients,
+            });
+        }
+
+        Err(unauthenticated("missing credentials"))
+    }
+}
+
+fn unauthenticated(message: &str) -> CustomError {
+    DomainError::new(ErrorCode::Unauthenticated, message.to_string()).into()
+}
+
+/// Authenticates the caller and checks it may access the `client_id` of the route. Ids that
+/// don't parse are left for the handler to reject.
+pub async fn authorize(
+    State(auth): State<Arc<Authenticator>>,
+    Path(params): Path<HashMap<String, String>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, CustomError> {
+    let principal = match auth.principal(request.headers()) {
+        Ok(principal) => principal,
+        Err(err) => return Ok(auth.challenged(err)),
+    };
+
+    let client_id = params
+        .get("client_id")
+        .and_then(|id| id.parse::<u32>().ok());
+    if let Some(client_id) = client_id {
+        if !principal.can_access(client_id) {
+            let message = format!("{} cannot access client {}", principal.name, client_id);
+            return Err(DomainError::new(ErrorCode::Forbidden, message)
+                .with_param("client_id", client_id)
+                .into());
+        }
+    }
+
+    request.extensions_mut().insert(principal);
+
+    Ok(next.run(request).await)
+}
+
+/// only lets authenticated admins through, used for the operational routes
+#[cfg(feature = "diagnostics")]
+pub async fn require_admin(
+    State(auth): State<Arc<Authenticator>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, CustomError> {
+    // the anonymous caller of a disabled authentication is no admin here
+    if !auth.enabled {
+        let message = "admin routes need authentication to be enabled".to_string();
+        return Err(DomainError::new(ErrorCode::Forbidden, message).into());
+    }
+
+    let principal = match auth.principal(request.headers()) {
+        Ok(principal) => principal,
+        Err(err) => return Ok(auth.challenged(err)),
+    };
+
+    if principal.role != Role::Admin {
+        let message = format!("{} is not an admin", principal.name);
+        return Err(DomainError::new(ErrorCode::Forbidden, message).into());
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{EncodingKey, Header};
+
+    use super::*;
+    use crate::config::app_config::{ApiKey, Auth};
+
+    const SECRET: &str = "secret";
+
+    fn authenticator(jwt_secret: Option<&str>) -> Authenticator {
+        Authenticator::new(&Auth {
+            enabled: true,
+            api_keys: vec![ApiKey {
+                name: "client-1".into(),
+                key: "key-1".into(),
+                role: Role::Client,
+                clients: vec![1],
+            }],
+            jwt_secret: jwt_secret.map(str::to_string),
+            jwt_issuer: Some("rinha".into()),
+        })
+    }
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn bearer(claims: serde_json::Value) -> HeaderMap {
+        let key = EncodingKey::from_secret(SECRET.as_bytes());
+        let token = jsonwebtoken::encode(&Header::default(), &claims, &key).unwrap();
+
+        headers("authorization", &format!("Bearer {}", token))
+    }
+
+    #[test]
+    fn resolves_api_keys() {
+        let auth = authenticator(None);
+
+        let principal = auth
+            .authenticate(&headers(API_KEY_HEADER, "key-1"))
+            .unwrap();
+        assert_eq!(principal.name, "client-1");
+        assert!(principal.can_access(1));
+        assert!(!principal.can_access(2));
+
+        assert!(auth.authenticate(&headers(API_KEY_HEADER, "key-")).is_err());
+        assert!(auth
+            .authenticate(&headers(API_KEY_HEADER, "key-12"))
+            .is_err());
+        assert!(auth.authenticate(&HeaderMap::new()).is_err());
+    }
+
+    #[test]
+    fn resolves_signed_tokens() {
+        let auth = authenticator(Some(SECRET));
+        let claims = serde_json::json!({
+            "sub": "ops",
+            "role": "admin",
+            "iss": "rinha",
+            "exp": u32::MAX,
+        });
+
+        let principal = auth.authenticate(&bearer(claims)).unwrap();
+        assert_eq!(principal.role, Role::Admin);
+        assert!(principal.can_access(2));
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        let auth = authenticator(Some(SECRET));
+        let expired = serde_json::json!({"sub": "ops", "iss": "rinha", "exp": 1});
+        let other_issuer = serde_json::json!({"sub": "ops", "iss": "other", "exp": u32::MAX});
+
+        assert!(auth.authenticate(&bearer(expired)).is_err());
+        assert!(auth.authenticate(&bearer(other_issuer)).is_err());
+        assert!(auth
+            .authenticate(&headers("authorization", "Bearer nope"))
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_tokens_when_jwt_is_not_configured() {
+        let claims = serde_json::json!({"sub": "ops", "iss": "rinha", "exp": u32::MAX});
+
+        assert!(authenticator(None).authenticate(&bearer(claims)).is_err());
+    }
+
+    #[test]
+    fn challenges_with_the_configured_schemes() {
+        let err = unauthenticated("missing credentials");
+        let response = authenticator(Some(SECRET)).challenged(err);
+
+        assert_eq!(response.status(), 401);
+        assert_eq!(
+            response.headers()[WWW_AUTHENTICATE],
+            r#"ApiKey header="X-Api-Key", Bearer"#
+        );
+    }
+}
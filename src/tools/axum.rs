@@ -1,11 +1,18 @@
 use axum::{
     async_trait,
-    extract::FromRequestParts,
-    http::{request::Parts, StatusCode},
+    body::Bytes,
+    extract::path::ErrorKind,
+    extract::rejection::PathRejection,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{header::CONTENT_TYPE, request::Parts, HeaderMap},
+    response::{IntoResponse, Response},
 };
-
-use crate::tools::error::CustomError;
 use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::error::Category;
+
+use crate::tools::error::{CustomError, RejectionError};
+use crate::tools::problem::{ErrorCode, Reason};
 
 pub struct Path<T>(pub T);
 
@@ -16,17 +23,239 @@ where
     T: DeserializeOwned + Send,
     S: Send + Sync,
 {
-    type Rejection = (StatusCode, CustomError);
+    type Rejection = CustomError;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         match axum::extract::Path::<T>::from_request_parts(parts, state).await {
             Ok(value) => Ok(Self(value.0)),
             Err(path_rejection) => {
-                let status = StatusCode::BAD_REQUEST;
-                let err = CustomError::Rejection(path_rejection.to_string());
+                let field = match &path_rejection {
+                    PathRejection::FailedToDeserializePathParams(err) => match err.kind() {
+                        ErrorKind::ParseErrorAtKey { key, .. }
+                        | ErrorKind::InvalidUtf8InPathParam { key } => Some(key.clone()),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                let reason = match field {
+                    Some(_) => Reason::InvalidParam,
+                    None => Reason::InvalidPath,
+                };
+                let detail = path_rejection.body_text();
+                let err = RejectionError::new(ErrorCode::InvalidRequest, reason, field, detail);
 
-                Err((status, err))
+                Err(CustomError::Rejection(err))
             }
         }
     }
 }
+
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for Json<T>
+where
+    // these trait bounds are copied from `impl FromRequest for axum::Json`
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = CustomError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !has_json_content_type(req.headers()) {
+            let detail = "expected request with `Content-Type: application/json`".to_string();
+            let reason = Reason::UnsupportedContentType;
+            let err = RejectionError::new(ErrorCode::UnsupportedMediaType, reason, None, detail);
+
+            return Err(CustomError::Rejection(err));
+        }
+
+        let bytes = Bytes::from_request(req, state).await.map_err(|rejection| {
+            let reason = Reason::UnreadableBody;
+            let detail = rejection.body_text();
+            let err = RejectionError::new(ErrorCode::InvalidRequest, reason, None, detail);
+            CustomError::Rejection(err)
+        })?;
+
+        let deserializer = &mut serde_json::Deserializer::from_slice(&bytes);
+        let value = serde_path_to_error::deserialize(deserializer).map_err(|err| {
+            let field = offending_field(&err);
+            let inner = err.into_inner();
+
+            // well-formed json with unexpected types or values is unprocessable,
+            // anything else is a plain bad request
+            let (code, reason) = match (inner.classify(), &field) {
+                (Category::Data, Some(_)) => (ErrorCode::InvalidBody, Reason::InvalidField),
+                (Category::Data, None) => (ErrorCode::InvalidBody, Reason::InvalidShape),
+                _ => (ErrorCode::InvalidRequest, Reason::MalformedJson),
+            };
+            let err = RejectionError::new(code, reason, field, inner.to_string());
+
+            CustomError::Rejection(err)
+        })?;
+
+        Ok(Self(value))
+    }
+}
+
+impl<T> IntoResponse for Json<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+fn has_json_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+
+    mime.eq_ignore_ascii_case("application/json")
+        || (mime.starts_with("application/") && mime.ends_with("+json"))
+}
+
+/// the path to the field that failed, missing fields being reported at their parent
+fn offending_field(err: &serde_path_to_error::Error<serde_json::Error>) -> Option<String> {
+    let path = err.path().to_string();
+
+    (path != ".").then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{HeaderValue, StatusCode};
+
+    use super::*;
+    use crate::domain::transaction::model::CreateTransactionPayload;
+
+    async fn extract(
+        content_type: &str,
+        body: &str,
+    ) -> Result<CreateTransactionPayload, RejectionError> {
+        let req = Request::builder()
+            .method("POST")
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        match Json::<CreateTransactionPayload>::from_request(req, &()).await {
+            Ok(Json(payload)) => Ok(payload),
+            Err(CustomError::Rejection(err)) => Err(err),
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+
+    async fn rejection(content_type: &str, body: &str) -> RejectionError {
+        extract(content_type, body).await.unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn valid_body_is_extracted() {
+        let body = r#"{"valor": 10, "tipo": "c", "descricao": "deposit"}"#;
+
+        let payload = extract("application/json; charset=utf-8", body)
+            .await
+            .unwrap();
+
+        assert_eq!(payload.amount, 10);
+    }
+
+    #[tokio::test]
+    async fn decimal_amount_is_unprocessable_at_its_field() {
+        let err = rejection(
+            "application/json",
+            r#"{"valor": 1.5, "tipo": "c", "descricao": "deposit"}"#,
+        )
+        .await;
+
+        assert_eq!(err.code.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.reason, Reason::InvalidField);
+        assert_eq!(err.field.as_deref(), Some("valor"));
+    }
+
+    #[tokio::test]
+    async fn null_description_is_unprocessable() {
+        let err = rejection(
+            "application/json",
+            r#"{"valor": 1, "tipo": "c", "descricao": null}"#,
+        )
+        .await;
+
+        assert_eq!(err.code.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.field.as_deref(), Some("descricao"));
+    }
+
+    #[tokio::test]
+    async fn missing_field_is_reported_as_the_shape() {
+        let err = rejection("application/json", r#"{"valor": 1, "tipo": "c"}"#).await;
+
+        assert_eq!(err.code.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.reason, Reason::InvalidShape);
+        assert_eq!(err.field, None);
+    }
+
+    #[tokio::test]
+    async fn truncated_json_is_a_bad_request() {
+        let err = rejection("application/json", r#"{"valor": 1, "tipo": "#).await;
+
+        assert_eq!(err.code.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.reason, Reason::MalformedJson);
+    }
+
+    #[tokio::test]
+    async fn other_content_types_are_unsupported() {
+        let err = rejection(
+            "text/plain",
+            r#"{"valor": 1, "tipo": "c", "descricao": "x"}"#,
+        )
+        .await;
+
+        assert_eq!(err.code.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(err.reason, Reason::UnsupportedContentType);
+    }
+
+    #[test]
+    fn json_content_types_are_recognized() {
+        let cases = [
+            (Some("application/json"), true),
+            (Some("Application/JSON; charset=utf-8"), true),
+            (Some("application/problem+json"), true),
+            (Some("text/plain"), false),
+            (Some("application/jsonx"), false),
+            (None, false),
+        ];
+
+        for (content_type, expected) in cases {
+            let mut headers = HeaderMap::new();
+            if let Some(content_type) = content_type {
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            }
+
+            assert_eq!(
+                has_json_content_type(&headers),
+                expected,
+                "{:?}",
+                content_type
+            );
+        }
+    }
+
+    #[test]
+    fn offending_field_is_the_path_to_it() {
+        let field = |json: &str| {
+            let deserializer = &mut serde_json::Deserializer::from_str(json);
+            let err = serde_path_to_error::deserialize::<_, CreateTransactionPayload>(deserializer)
+                .unwrap_err();
+            offending_field(&err)
+        };
+
+        assert_eq!(field(r#"{"valor": "1"}"#).as_deref(), Some("valor"));
+        assert_eq!(field(r#"{"valor": 1}"#), None);
+        assert_eq!(field("[]"), None);
+    }
+}
@@ -0,0 +1,306 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::app_config;
+use crate::tools::metrics::{BREAKER_REJECTIONS_COUNTER, BREAKER_STATE_GAUGE};
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    HalfOpen,
+    Open,
+}
+
+impl BreakerState {
+    /// value of the `circuit_breaker_state` gauge
+    fn as_gauge(&self) -> i64 {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::HalfOpen => 1,
+            BreakerState::Open => 2,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("circuit breaker for {0} is open")]
+pub struct BreakerOpen(pub &'static str);
+
+/// handed out by [`CircuitBreaker::allow`], ties the outcome of a call to the state it was
+/// let through in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ticket {
+    generation: u64,
+}
+
+struct Inner {
+    state: BreakerState,
+    // bumped on every transition, outcomes of calls let through before it are ignored
+    generation: u64,
+    consecutive_failures: u32,
+    opened_at: Instant,
+    probes: u32,
+    probing_since: Instant,
+}
+
+/// Stops calling a dependency after `failure_threshold` consecutive failures. Calls fail fast
+/// while open; once `open_duration` elapses up to `half_open_probes` calls are let through, and
+/// the first outcome of those probes decides whether the breaker closes or opens again.
+pub struct CircuitBreaker {
+    name: &'static str,
+    enabled: bool,
+    failure_threshold: u32,
+    open_duration: Duration,
+    half_open_probes: u32,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, conf: &app_config::Breaker) -> Self {
+        BREAKER_STATE_GAUGE
+            .with_label_values(&[name])
+            .set(BreakerState::Closed.as_gauge());
+
+        Self {
+            name,
+            enabled: conf.enabled,
+            failure_threshold: conf.failure_threshold,
+            open_duration: conf.open_duration,
+            half_open_probes: conf.half_open_probes,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                generation: 0,
+                consecutive_failures: 0,
+                opened_at: Instant::now(),
+                probes: 0,
+                probing_since: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// whether a call may go through, must be followed by [`Self::record`] or [`Self::forget`]
+    /// with the returned ticket when it does
+    pub fn allow(&self) -> Result<Ticket, BreakerOpen> {
+        if !self.enabled {
+            return Ok(Ticket { generation: 0 });
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let allowed = match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open if inner.opened_at.elapsed() >= self.open_duration => {
+                self.transition(&mut inner, BreakerState::HalfOpen);
+                inner.probes = 1;
+                inner.probing_since = Instant::now();
+                true
+            }
+            BreakerState::Open => false,
+            // probes that never reported back (e.g. cancelled requests) don't block it forever
+            BreakerState::HalfOpen
+                if inner.probes < self.half_open_probes
+                    || inner.probing_since.elapsed() >= self.open_duration =>
+            {
+                inner.probes += 1;
+                inner.probing_since = Instant::now();
+                true
+            }
+            BreakerState::HalfOpen => false,
+        };
+
+        if allowed {
+            Ok(Ticket {
+                generation: inner.generation,
+            })
+        } else {
+            BREAKER_REJECTIONS_COUNTER
+                .with_label_values(&[self.name])
+                .inc();
+            Err(BreakerOpen(self.name))
+        }
+    }
+
+    pub fn record(&self, ticket: Ticket, success: bool) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        // late outcome of a call let through before the last transition, e.g. a request that
+        // started while closed finishing while probing
+        if ticket.generation != inner.generation {
+            return;
+        }
+
+        match (inner.state, success) {
+            (BreakerState::Closed, true) => inner.consecutive_failures = 0,
+            (BreakerState::Closed, false) => {
+                inner.consecutive_failures += 1;
+                if inner.consecutive_failures >= self.failure_threshold {
+                    self.open(&mut inner);
+                }
+            }
+            (BreakerState::HalfOpen, true) => {
+                inner.consecutive_failures = 0;
+                inner.probes = 0;
+                self.transition(&mut inner, BreakerState::Closed);
+            }
+            (BreakerState::HalfOpen, false) => self.open(&mut inner),
+            // nothing is let through while open
+            (BreakerState::Open, _) => {}
+        }
+    }
+
+    /// gives back a call that was allowed but whose outcome says nothing about the dependency
+    pub fn forget(&self, ticket: Ticket) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == BreakerState::HalfOpen && ticket.generation == inner.generation {
+            inner.probes = inner.probes.saturating_sub(1);
+        }
+    }
+
+    fn open(&self, inner: &mut Inner) {
+        inner.opened_at = Instant::now();
+        inner.probes = 0;
+        self.transition(inner, BreakerState::Open);
+    }
+
+    fn transition(&self, inner: &mut Inner, state: BreakerState) {
+        if inner.state != state {
+            tracing::warn!(
+                "Circuit breaker for {} moved from {:?} to {:?}",
+                self.name,
+                inner.state,
+                state
+            );
+            inner.generation += 1;
+        }
+
+        inner.state = state;
+        BREAKER_STATE_GAUGE
+            .with_label_values(&[self.name])
+            .set(state.as_gauge());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(open_duration: Duration, half_open_probes: u32) -> CircuitBreaker {
+        let conf = app_config::Breaker {
+            enabled: true,
+            failure_threshold: 2,
+            open_duration,
+            half_open_probes,
+        };
+
+        CircuitBreaker::new("test", &conf)
+    }
+
+    fn fail(breaker: &CircuitBreaker) {
+        let ticket = breaker.allow().unwrap();
+        breaker.record(ticket, false);
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(Duration::from_secs(60), 1);
+
+        fail(&breaker);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        fail(&breaker);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.allow().is_err());
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let breaker = breaker(Duration::from_secs(60), 1);
+
+        fail(&breaker);
+        let ticket = breaker.allow().unwrap();
+        breaker.record(ticket, true);
+        fail(&breaker);
+
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn probe_outcome_decides_half_open() {
+        let breaker = breaker(Duration::ZERO, 1);
+        fail(&breaker);
+        fail(&breaker);
+
+        let probe = breaker.allow().unwrap();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.record(probe, false);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        let probe = breaker.allow().unwrap();
+        breaker.record(probe, true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn half_open_limits_probes() {
+        let breaker = breaker(Duration::from_millis(50), 1);
+        fail(&breaker);
+        fail(&breaker);
+        std::thread::sleep(Duration::from_millis(60));
+
+        let probe = breaker.allow().unwrap();
+        assert!(breaker.allow().is_err());
+
+        breaker.forget(probe);
+        assert!(breaker.allow().is_ok());
+    }
+
+    #[test]
+    fn late_outcomes_are_ignored() {
+        let breaker = breaker(Duration::ZERO, 1);
+        let early = breaker.allow().unwrap();
+        let late = breaker.allow().unwrap();
+        breaker.record(early, false);
+        fail(&breaker);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        let probe = breaker.allow().unwrap();
+        // a call let through while closed doesn't close or reopen the breaker
+        breaker.record(late, true);
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.record(late, false);
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        breaker.record(probe, true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn disabled_always_allows() {
+        let conf = app_config::Breaker {
+            enabled: false,
+            failure_threshold: 1,
+            open_duration: Duration::from_secs(60),
+            half_open_probes: 1,
+        };
+        let breaker = CircuitBreaker::new("test", &conf);
+
+        fail(&breaker);
+        fail(&breaker);
+
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow().is_ok());
+    }
+}
@@ -1,144 +1,941 @@
+use std::fs;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use anyhow::Context;
 use axum::async_trait;
+use hyper::client::HttpConnector;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use libsql::params::Params;
 use libsql::{Builder, Rows, TransactionBehavior};
+use rustls::{ClientConfig, RootCertStore};
 use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tracing::Instrument;
 
-use crate::config::app_config::AppConfig;
-use crate::tools::metrics::{DeferredObserve, OPS_HISTOGRAM};
+use crate::config::app_config::{self, AppConfig};
+use crate::tools::breaker::{BreakerOpen, BreakerState, CircuitBreaker, Ticket};
+use crate::tools::metrics::{
+    DeferredObserve, GaugeGuard, DB_POOL_CONNECTIONS_GAUGE, DB_POOL_WAITERS_GAUGE, OPS_HISTOGRAM,
+    SHED_COUNTER, SLOW_QUERIES_COUNTER, SQL_HISTOGRAM,
+};
+use crate::tools::overload::{self, DeadlineExceeded, GaveUp};
+use crate::tools::request_id;
 
+pub type DbResult<T> = Result<T, DbError>;
+
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
+// codes sqld reports refused statements with
+const REJECTED_CODES: &[&str] = &["SQLITE_BUSY", "SQLITE_LOCKED"];
+
+/// errors raised by the pool itself, next to the ones coming from libsql
+#[derive(Debug, thiserror::Error)]
+pub enum DbError {
+    #[error(transparent)]
+    Libsql(#[from] libsql::Error),
+
+    #[error("no database connection available after {0:?}")]
+    PoolTimeout(Duration),
+
+    #[error("connection pool is closed")]
+    PoolClosed,
+
+    #[error(transparent)]
+    CircuitOpen(#[from] BreakerOpen),
+
+    #[error(transparent)]
+    Deadline(DeadlineExceeded),
+
+    #[error("commit failed: {0}")]
+    Commit(#[source] libsql::Error),
+}
+
+/// failures worth trying again, see [`crate::tools::retry`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transient {
+    /// sqld refused the statement (busy or locked), nothing was applied
+    Rejected,
+    /// the connection failed or sqld answered with a 5xx, the statement may have run
+    Unreachable,
+}
+
+impl DbError {
+    /// the database is saturated or unreachable rather than broken, the request may succeed later
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, DbError::PoolTimeout(_) | DbError::CircuitOpen(_))
+    }
+
+    /// only an unreachable sqld counts as an outage, a busy database or a failed statement does not
+    fn trips_breaker(&self) -> bool {
+        match self {
+            DbError::Libsql(err) | DbError::Commit(err) => {
+                transient(err) == Some(Transient::Unreachable)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn transient(&self) -> Option<Transient> {
+        match self {
+            DbError::Libsql(err) => transient(err),
+            // a commit that may have gone through must not be replayed
+            DbError::Commit(err) => transient(err).filter(|t| *t == Transient::Rejected),
+            // the pool and the breaker already failed fast on purpose
+            DbError::PoolTimeout(_)
+            | DbError::PoolClosed
+            | DbError::CircuitOpen(_)
+            | DbError::Deadline(_) => None,
+        }
+    }
+}
+
+fn transient(err: &libsql::Error) -> Option<Transient> {
+    match err {
+        // extended result codes keep the primary one in the low byte
+        libsql::Error::SqliteFailure(code, _) => {
+            matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED).then_some(Transient::Rejected)
+        }
+        libsql::Error::ConnectionFailed(_) => Some(Transient::Unreachable),
+        libsql::Error::Hrana(err) | libsql::Error::WriteDelegation(err) => {
+            remote_transient(err.as_ref())
+        }
+        _ => None,
+    }
+}
+
+/// Errors coming from sqld are private to libsql: transport failures are found in their
+/// sources, and the http status or sqlite code sqld answered with in their message.
+fn remote_transient(err: &(dyn std::error::Error + 'static)) -> Option<Transient> {
+    let mut cause = Some(err);
+    while let Some(err) = cause {
+        let transport = err.is::<std::io::Error>()
+            || err.downcast_ref::<hyper::Error>().is_some_and(|err| {
+                err.is_connect()
+                    || err.is_closed()
+                    || err.is_incomplete_message()
+                    || err.is_timeout()
+            });
+        if transport {
+            return Some(Transient::Unreachable);
+        }
+        cause = err.source();
+    }
+
+    let message = err.to_string();
+    if REJECTED_CODES.iter().any(|code| message.contains(code)) {
+        return Some(Transient::Rejected);
+    }
+
+    let status = message
+        .split_once("status=")
+        .and_then(|(_, rest)| rest.get(..3))
+        .and_then(|status| status.parse::<u16>().ok());
+    match status {
+        Some(500..=599) => Some(Transient::Unreachable),
+        Some(_) => None,
+        None => message
+            .starts_with("http error")
+            .then_some(Transient::Unreachable),
+    }
+}
+
+/// classifies the first database error found in the chain of `err`
+pub fn transient_cause(err: &anyhow::Error) -> Option<Transient> {
+    err.chain()
+        .find_map(|cause| {
+            if let Some(err) = cause.downcast_ref::<DbError>() {
+                return Some(err.transient());
+            }
+            cause.downcast_ref::<libsql::Error>().map(transient)
+        })
+        .flatten()
+}
+
+/// `operation` names the statement in metrics, spans and slow query logs
 #[async_trait]
 pub trait Database: Send + Sync {
-    async fn execute_batch(&self, sql: &str) -> libsql::Result<()>;
-    async fn query(&self, sql: &str) -> libsql::Result<Rows>;
-    async fn transaction(&self, behavior: TransactionBehavior)
-        -> libsql::Result<LibsqlTransaction>;
+    async fn execute_batch(&self, operation: &'static str, sql: &str) -> DbResult<()>;
+    async fn query(&self, operation: &'static str, sql: &str) -> DbResult<Rows>;
+    async fn transaction(&self, behavior: TransactionBehavior) -> DbResult<LibsqlTransaction>;
 }
 
 pub struct PooledLibsqlDatabase {
     db: libsql::Database,
+    pool: Arc<Pool>,
+    acquire_timeout: Duration,
+    slow_query_threshold: Duration,
+    breaker: Arc<CircuitBreaker>,
+}
+
+/// idle connections kept for reuse, and the permits bounding how many are checked out
+struct Pool {
     connections: Mutex<Vec<(libsql::Connection, Instant)>>,
-    max_idle: Duration,
+    max_idle_ms: AtomicU64,
+    max_connections: AtomicUsize,
+    // permits still to be taken out of circulation after a shrink
+    retiring: AtomicUsize,
     semaphore: Arc<Semaphore>,
 }
 
+/// a connection taken from the pool along with its permit, dropping it discards the connection
+struct Checkout {
+    pool: Arc<Pool>,
+    conn: libsql::Connection,
+    permit: OwnedSemaphorePermit,
+    // the statements run on it are recorded against the breaker with it
+    ticket: Ticket,
+}
+
+/// holds on to its connection until committed or rolled back, so no other request can run
+/// statements within it
 pub struct LibsqlTransaction {
     tx: libsql::Transaction,
+    checkout: Checkout,
+    slow_query_threshold: Duration,
+    breaker: Arc<CircuitBreaker>,
 }
 
 impl PooledLibsqlDatabase {
-    pub async fn new(conf: &AppConfig) -> Self {
-        let url = format!("http://{}:{}", conf.db.host, conf.db.port);
-        let db = Builder::new_remote(url.clone(), "".to_string())
+    pub async fn new(conf: &AppConfig) -> anyhow::Result<Self> {
+        let url = format!("{}://{}:{}", conf.db.scheme, conf.db.host, conf.db.port);
+        let db = Builder::new_remote(url, Self::auth_token(&conf.db)?)
+            .connector(Self::connector(&conf.db)?)
             .build()
-            .await
-            .unwrap();
+            .await?;
 
-        let connections = Mutex::new(Vec::new());
-        let max_idle = conf.db.max_idle;
-        let max_connections = conf.db.max_connections;
-        let semaphore = Arc::new(Semaphore::new(max_connections));
+        let pool = Arc::new(Pool::new(conf.db.max_idle, conf.db.max_connections));
 
-        Self {
+        Ok(Self {
             db,
-            connections,
-            max_idle,
-            semaphore,
+            pool,
+            acquire_timeout: conf.db.acquire_timeout,
+            slow_query_threshold: conf.db.slow_query_threshold,
+            breaker: Arc::new(CircuitBreaker::new("db", &conf.breakers.db)),
+        })
+    }
+
+    fn auth_token(conf: &app_config::Database) -> anyhow::Result<String> {
+        match (&conf.auth_token, &conf.auth_token_file) {
+            (Some(token), _) => Ok(token.clone()),
+            (None, Some(file)) => fs::read_to_string(file)
+                .map(|token| token.trim().to_string())
+                .with_context(|| format!("failed to read auth token from {}", file.display())),
+            (None, None) => Ok(String::new()),
         }
     }
 
-    async fn get_connection(&self) -> libsql::Result<(libsql::Connection, OwnedSemaphorePermit)> {
+    /// plain http or tls depending on the url scheme, trusting the system roots plus `ca_cert`
+    fn connector(conf: &app_config::Database) -> anyhow::Result<HttpsConnector<HttpConnector>> {
+        let mut roots = RootCertStore::empty();
+        let native = rustls_native_certs::load_native_certs()
+            .context("failed to load system root certificates")?;
+        roots.add_parsable_certificates(&native);
+
+        if let Some(file) = &conf.ca_cert {
+            let pem = fs::read(file)
+                .with_context(|| format!("failed to read CA certificate {}", file.display()))?;
+            let certs = rustls_pemfile::certs(&mut pem.as_slice())
+                .with_context(|| format!("invalid PEM in {}", file.display()))?;
+
+            let (added, _) = roots.add_parsable_certificates(&certs);
+            anyhow::ensure!(added > 0, "no usable certificate in {}", file.display());
+        }
+
+        let tls = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_nodelay(true);
+
+        Ok(HttpsConnectorBuilder::new()
+            .with_tls_config(tls)
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(http))
+    }
+
+    pub fn set_max_idle(&self, max_idle: Duration) {
+        self.pool
+            .max_idle_ms
+            .store(max_idle.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_max_connections(&self, max_connections: usize) {
+        self.pool.resize(max_connections);
+    }
+
+    /// runs a trivial query through the pool
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        self.query("ping", "SELECT 1").await?;
+
+        Ok(())
+    }
+
+    pub fn breaker_state(&self) -> BreakerState {
+        self.breaker.state()
+    }
+
+    /// refuses new checkouts and drops the idle connections, used on shutdown
+    pub async fn close(&self) {
+        self.pool.semaphore.close();
+        self.pool.connections.lock().await.clear();
+
+        tracing::info!("Database connection pool closed");
+    }
+
+    /// waits at most `acquire_timeout` for a permit, so a slow sqld sheds load instead of
+    /// piling up requests
+    async fn get_connection(&self) -> DbResult<Checkout> {
+        let ticket = self.breaker.allow()?;
+
         let _deferred_observe = DeferredObserve::new(&OPS_HISTOGRAM, &["acquire_connection"]);
-        let permit = self.semaphore.clone().acquire_owned().await.unwrap();
+        let acquire = self
+            .pool
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .instrument(tracing::info_span!("acquire_connection"));
+        let waiting = GaugeGuard::new(DB_POOL_WAITERS_GAUGE.clone());
+        let permit = overload::bounded(self.acquire_timeout, acquire).await;
+        drop(waiting);
+
+        // pool errors don't say anything about sqld, the call is not counted either way
+        let permit = match permit {
+            Ok(Ok(permit)) => permit,
+            Ok(Err(_)) => {
+                self.breaker.forget(ticket);
+                return Err(DbError::PoolClosed);
+            }
+            Err(GaveUp::Limit) => {
+                self.breaker.forget(ticket);
+                SHED_COUNTER.with_label_values(&["pool"]).inc();
+                return Err(DbError::PoolTimeout(self.acquire_timeout));
+            }
+            Err(GaveUp::Deadline(exceeded)) => {
+                self.breaker.forget(ticket);
+                return Err(DbError::Deadline(exceeded));
+            }
+        };
 
         tracing::debug!(
             "Acquired permit, available slots: {}",
-            self.semaphore.available_permits()
+            self.pool.semaphore.available_permits()
         );
 
-        let mut connections = self.connections.lock().await;
+        let mut connections = self.pool.connections.lock().await;
+
+        let max_idle = self.pool.max_idle();
+        connections.retain(|(_, last_used)| last_used.elapsed() < max_idle);
 
-        connections.retain(|(_, last_used)| last_used.elapsed() < self.max_idle);
+        let reused = connections.pop();
+        self.pool.observe(connections.len());
 
-        if let Some((existing_conn, _)) = connections.pop() {
+        if let Some((conn, _)) = reused {
             tracing::debug!("Reusing existing connection");
-            return Ok((existing_conn, permit));
+            return Ok(self.pool.checkout(conn, permit, ticket));
         }
 
         tracing::debug!("Creating new connection");
-        Ok((self.db.connect()?, permit))
+        let conn = self.db.connect().map_err(DbError::from);
+        if let Err(err) = &conn {
+            if err.trips_breaker() {
+                self.breaker.record(ticket, false);
+            } else {
+                self.breaker.forget(ticket);
+            }
+        }
+
+        Ok(self.pool.checkout(conn?, permit, ticket))
     }
+}
 
-    async fn return_connection(&self, conn: libsql::Connection, _permit: OwnedSemaphorePermit) {
-        let mut connections = self.connections.lock().await;
-        if connections.len() < self.semaphore.available_permits() {
-            connections.push((conn, Instant::now()));
+impl Pool {
+    fn new(max_idle: Duration, max_connections: usize) -> Self {
+        Self {
+            connections: Mutex::new(Vec::new()),
+            max_idle_ms: AtomicU64::new(max_idle.as_millis() as u64),
+            max_connections: AtomicUsize::new(max_connections),
+            retiring: AtomicUsize::new(0),
+            semaphore: Arc::new(Semaphore::new(max_connections)),
         }
-        // permit is implicitly dropped here
+    }
+
+    /// resizes the pool without touching in-flight requests: growing first cancels the
+    /// retirements still pending and adds the remaining permits right away, shrinking retires
+    /// the excess permits one at a time as they are released
+    fn resize(self: &Arc<Self>, max_connections: usize) {
+        let current = self
+            .max_connections
+            .swap(max_connections, Ordering::Relaxed);
+
+        if max_connections > current {
+            let growth = max_connections - current;
+            let cancelled = self
+                .retiring
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |retiring| {
+                    Some(retiring.saturating_sub(growth))
+                })
+                .map_or(0, |retiring| retiring.min(growth));
+
+            self.semaphore.add_permits(growth - cancelled);
+        } else if max_connections < current {
+            self.retiring
+                .fetch_add(current - max_connections, Ordering::Relaxed);
+
+            let pool = self.clone();
+            tokio::spawn(async move { pool.retire().await });
+        }
+    }
+
+    async fn retire(&self) {
+        while self.retiring.load(Ordering::Relaxed) > 0 {
+            let Ok(permit) = self.semaphore.clone().acquire_owned().await else {
+                return;
+            };
+
+            // a grow may have cancelled the retirement while waiting for the permit
+            let retired = self
+                .retiring
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |retiring| {
+                    retiring.checked_sub(1)
+                })
+                .is_ok();
+            if !retired {
+                return;
+            }
+            permit.forget();
+        }
+    }
+
+    fn max_idle(&self) -> Duration {
+        Duration::from_millis(self.max_idle_ms.load(Ordering::Relaxed))
+    }
+
+    fn checkout(
+        self: &Arc<Self>,
+        conn: libsql::Connection,
+        permit: OwnedSemaphorePermit,
+        ticket: Ticket,
+    ) -> Checkout {
+        Checkout {
+            pool: self.clone(),
+            conn,
+            permit,
+            ticket,
+        }
+    }
+
+    /// connections in use are the permits handed out, idle ones are those kept for reuse
+    fn observe(&self, idle: usize) {
+        let in_use = self
+            .max_connections
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.semaphore.available_permits());
+
+        DB_POOL_CONNECTIONS_GAUGE
+            .with_label_values(&["in_use"])
+            .set(in_use as i64);
+        DB_POOL_CONNECTIONS_GAUGE
+            .with_label_values(&["idle"])
+            .set(idle as i64);
+    }
+}
+
+impl Checkout {
+    /// hands the connection back for reuse, to be called only once it is known to be clean
+    async fn release(self) {
+        let mut connections = self.pool.connections.lock().await;
+        if connections.len() < self.pool.semaphore.available_permits() {
+            connections.push((self.conn, Instant::now()));
+        }
+
+        drop(self.permit);
+        self.pool.observe(connections.len());
     }
 }
 
 #[async_trait]
 impl Database for PooledLibsqlDatabase {
-    async fn execute_batch(&self, sql: &str) -> libsql::Result<()> {
-        let (conn, permit) = self.get_connection().await?;
-        let result = conn.execute_batch(sql).await;
-        self.return_connection(conn, permit).await;
+    #[tracing::instrument(
+        name = "sql",
+        skip_all,
+        fields(db.operation = operation, db.statement = %sql_shape(sql))
+    )]
+    async fn execute_batch(&self, operation: &'static str, sql: &str) -> DbResult<()> {
+        let checkout = self.get_connection().await?;
+        let ticket = checkout.ticket;
+        let tagged = request_id::tag_sql(sql);
+        let statement = checkout.conn.execute_batch(&tagged);
+        let result = timed(operation, sql, self.slow_query_threshold, statement).await;
+        checkout.release().await;
 
-        result
+        recorded(&self.breaker, ticket, result.map_err(DbError::from))
     }
 
-    async fn query(&self, sql: &str) -> libsql::Result<Rows> {
-        let (conn, permit) = self.get_connection().await?;
-        let result = conn.query(sql, Params::None).await;
-        self.return_connection(conn, permit).await;
+    #[tracing::instrument(
+        name = "sql",
+        skip_all,
+        fields(db.operation = operation, db.statement = %sql_shape(sql))
+    )]
+    async fn query(&self, operation: &'static str, sql: &str) -> DbResult<Rows> {
+        let checkout = self.get_connection().await?;
+        let ticket = checkout.ticket;
+        let tagged = request_id::tag_sql(sql);
+        let statement = checkout.conn.query(&tagged, Params::None);
+        let result = timed(operation, sql, self.slow_query_threshold, statement).await;
+        checkout.release().await;
 
-        result
+        recorded(&self.breaker, ticket, result.map_err(DbError::from))
     }
 
-    async fn transaction(
-        &self,
-        behavior: TransactionBehavior,
-    ) -> libsql::Result<LibsqlTransaction> {
-        let (conn, permit) = self.get_connection().await?;
-        let tx = conn
-            .transaction_with_behavior(behavior)
-            .await
-            .map(|tx| LibsqlTransaction { tx });
-        self.return_connection(conn, permit).await;
+    async fn transaction(&self, behavior: TransactionBehavior) -> DbResult<LibsqlTransaction> {
+        let checkout = self.get_connection().await?;
+        let ticket = checkout.ticket;
+        let begin = checkout.conn.transaction_with_behavior(behavior);
+        let tx = timed("begin", "BEGIN", self.slow_query_threshold, begin).await;
 
-        tx
+        // the connection is only released when the transaction ends
+        let tx = tx.map(|tx| LibsqlTransaction {
+            tx,
+            checkout,
+            slow_query_threshold: self.slow_query_threshold,
+            breaker: self.breaker.clone(),
+        });
+
+        recorded(&self.breaker, ticket, tx.map_err(DbError::from))
     }
 }
 
 impl LibsqlTransaction {
-    pub async fn commit(self) -> libsql::Result<()> {
-        self.tx.commit().await
+    #[tracing::instrument(skip_all)]
+    pub async fn commit(self) -> DbResult<()> {
+        let ticket = self.checkout.ticket;
+        let commit = self.tx.commit();
+        let result = timed("commit", "COMMIT", self.slow_query_threshold, commit).await;
+        if result.is_ok() {
+            self.checkout.release().await;
+        }
+
+        recorded(&self.breaker, ticket, result.map_err(DbError::Commit))
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn rollback(self) -> DbResult<()> {
+        let ticket = self.checkout.ticket;
+        let rollback = self.tx.rollback();
+        let result = timed("rollback", "ROLLBACK", self.slow_query_threshold, rollback).await;
+        if result.is_ok() {
+            self.checkout.release().await;
+        }
+
+        recorded(&self.breaker, ticket, result.map_err(DbError::from))
     }
 
-    pub async fn rollback(self) -> libsql::Result<()> {
-        self.tx.rollback().await
+    /// commits if `result` is ok, otherwise rolls back and returns the original error
+    pub async fn finish<T, E>(self, result: Result<T, E>, context: &'static str) -> Result<T, E>
+    where
+        E: From<anyhow::Error>,
+    {
+        match result {
+            Ok(value) => {
+                self.commit().await.context(context)?;
+                Ok(value)
+            }
+            Err(err) => {
+                if let Err(rollback) = self.rollback().await {
+                    tracing::warn!("Failed to roll back transaction: {}", rollback);
+                }
+                Err(err)
+            }
+        }
     }
 }
 
 #[async_trait]
 impl Database for LibsqlTransaction {
-    async fn execute_batch(&self, sql: &str) -> libsql::Result<()> {
-        self.tx.execute_batch(sql).await
+    #[tracing::instrument(
+        name = "sql",
+        skip_all,
+        fields(db.operation = operation, db.statement = %sql_shape(sql))
+    )]
+    async fn execute_batch(&self, operation: &'static str, sql: &str) -> DbResult<()> {
+        let tagged = request_id::tag_sql(sql);
+        let statement = self.tx.execute_batch(&tagged);
+        let result = timed(operation, sql, self.slow_query_threshold, statement).await;
+
+        recorded(
+            &self.breaker,
+            self.checkout.ticket,
+            result.map_err(DbError::from),
+        )
+    }
+
+    #[tracing::instrument(
+        name = "sql",
+        skip_all,
+        fields(db.operation = operation, db.statement = %sql_shape(sql))
+    )]
+    async fn query(&self, operation: &'static str, sql: &str) -> DbResult<Rows> {
+        let tagged = request_id::tag_sql(sql);
+        let statement = self.tx.query(&tagged, Params::None);
+        let result = timed(operation, sql, self.slow_query_threshold, statement).await;
+
+        recorded(
+            &self.breaker,
+            self.checkout.ticket,
+            result.map_err(DbError::from),
+        )
+    }
+
+    async fn transaction(&self, _behavior: TransactionBehavior) -> DbResult<LibsqlTransaction> {
+        Err(libsql::Error::Misuse("nested transactions are not supported".to_string()).into())
+    }
+}
+
+/// records the outcome of a database call on the breaker and passes it through
+fn recorded<T>(breaker: &CircuitBreaker, ticket: Ticket, result: DbResult<T>) -> DbResult<T> {
+    breaker.record(
+        ticket,
+        result
+            .as_ref()
+            .map_or_else(|err| !err.trips_breaker(), |_| true),
+    );
+    result
+}
+
+/// observes how long `statement` took under `operation`, logging its shape when it was slow
+async fn timed<F: Future>(
+    operation: &'static str,
+    sql: &str,
+    slow_query_threshold: Duration,
+    statement: F,
+) -> F::Output {
+    let start = Instant::now();
+    let output = statement.await;
+    let elapsed = start.elapsed();
+
+    SQL_HISTOGRAM
+        .with_label_values(&[operation])
+        .observe(elapsed.as_secs_f64());
+
+    if elapsed >= slow_query_threshold {
+        SLOW_QUERIES_COUNTER.with_label_values(&[operation]).inc();
+        tracing::warn!(
+            "Slow query {} took {:?}: {}",
+            operation,
+            elapsed,
+            sql_shape(sql)
+        );
+    }
+
+    output
+}
+
+/// the statement with its literal values replaced by `?`, so it can be logged safely
+pub fn sql_shape(sql: &str) -> String {
+    let mut shape = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    // digits right after an identifier character are part of the identifier
+    let mut in_identifier = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                // a doubled quote is an escaped quote inside the literal
+                while let Some(c) = chars.next() {
+                    if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                        break;
+                    }
+                }
+                shape.push('?');
+                in_identifier = false;
+            }
+            // comments, such as the request id tags, are dropped
+            '/' if chars.next_if_eq(&'*').is_some() => {
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+                while chars.next_if_eq(&' ').is_some() {}
+                in_identifier = false;
+            }
+            // blob literals, x'...'
+            'x' | 'X' if !in_identifier && chars.peek() == Some(&'\'') => {}
+            c if c.is_ascii_digit() && !in_identifier => {
+                // hex and exponent notations included
+                while chars
+                    .next_if(|c| c.is_ascii_alphanumeric() || *c == '.')
+                    .is_some()
+                {}
+                shape.push('?');
+            }
+            c => {
+                in_identifier = c.is_alphanumeric() || c == '_' || c == '"';
+                shape.push(c);
+            }
+        }
+    }
+
+    shape
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use config::{Config, File};
+
+    use super::*;
+
+    // self-signed, only ever parsed
+    const CA_CERT: &str = "\
+-----BEGIN CERTIFICATE-----\n\
+MIIBhDCCASugAwIBAgIUKkTjbebhJwnW23xQdvHinSfiQ+4wCgYIKoZIzj0EAwIw\n\
+FzEVMBMGA1UEAwwMc3FsZCB0ZXN0IENBMCAXDTI2MTAxOTA5MjEyNFoYDzIxMjYw\n\
+OTI1MDkyMTI0WjAXMRUwEwYDVQQDDAxzcWxkIHRlc3QgQ0EwWTATBgcqhkjOPQIB\n\
+BggqhkjOPQMBBwNCAATV4yQ7+7G+soX8ye2bZ5xzA6OJnsSk6IHtoUgkQRwnHlm9\n\
+WP+QggI9n6mQOsKtL4RdQZ4a6hZXARVdvf1yy1G3o1MwUTAdBgNVHQ4EFgQU2qSG\n\
+VEi3rUVh/BmYIIAw24dbYrEwHwYDVR0jBBgwFoAU2qSGVEi3rUVh/BmYIIAw24db\n\
+YrEwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNHADBEAiAt1iH0TM8UD4Jy\n\
+9+spSDzPlQ23RS9sXsr7AeeGWKQyTwIgMTenOVqTq4CSIwZxboYTAFv6tHbZz/Nt\n\
+lFef+PxEyEg=\n\
+-----END CERTIFICATE-----\n";
+
+    fn db_config() -> app_config::Database {
+        let defaults = concat!(env!("CARGO_MANIFEST_DIR"), "/src/config/env/defaults.yml");
+
+        Config::builder()
+            .add_source(File::with_name(defaults))
+            .build()
+            .and_then(|conf| conf.try_deserialize::<AppConfig>())
+            .unwrap()
+            .db
     }
 
-    async fn query(&self, sql: &str) -> libsql::Result<Rows> {
-        self.tx.query(sql, Params::None).await
+    fn temp_file(contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rinha-{}", uuid::Uuid::new_v4()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn auth_token_comes_from_the_config_or_its_file() {
+        let mut conf = db_config();
+        assert_eq!(PooledLibsqlDatabase::auth_token(&conf).unwrap(), "");
+
+        conf.auth_token_file = Some(temp_file("  file-token\n"));
+        assert_eq!(
+            PooledLibsqlDatabase::auth_token(&conf).unwrap(),
+            "file-token"
+        );
+
+        conf.auth_token = Some("inline-token".into());
+        assert_eq!(
+            PooledLibsqlDatabase::auth_token(&conf).unwrap(),
+            "inline-token"
+        );
+
+        conf.auth_token = None;
+        conf.auth_token_file = Some(PathBuf::from("/nonexistent/sqld.jwt"));
+        assert!(PooledLibsqlDatabase::auth_token(&conf).is_err());
     }
 
-    async fn transaction(
-        &self,
-        _behavior: TransactionBehavior,
-    ) -> libsql::Result<LibsqlTransaction> {
-        Err(libsql::Error::Misuse(
-            "nested transactions are not supported".to_string(),
-        ))
+    #[test]
+    fn ca_cert_is_trusted_when_usable() {
+        let mut conf = db_config();
+        assert!(PooledLibsqlDatabase::connector(&conf).is_ok());
+
+        conf.ca_cert = Some(temp_file(CA_CERT));
+        assert!(PooledLibsqlDatabase::connector(&conf).is_ok());
+
+        conf.ca_cert = Some(temp_file("not a certificate"));
+        let err = PooledLibsqlDatabase::connector(&conf).unwrap_err();
+        assert!(
+            err.to_string().contains("no usable certificate"),
+            "{:#}",
+            err
+        );
+
+        conf.ca_cert = Some(PathBuf::from("/nonexistent/ca.pem"));
+        assert!(PooledLibsqlDatabase::connector(&conf).is_err());
+    }
+
+    fn remote(message: &str) -> libsql::Error {
+        libsql::Error::Hrana(message.into())
+    }
+
+    #[test]
+    fn busy_and_locked_are_rejected() {
+        let busy = libsql::Error::SqliteFailure(SQLITE_BUSY, "database is locked".into());
+        // SQLITE_LOCKED_SHAREDCACHE
+        let locked = libsql::Error::SqliteFailure(SQLITE_LOCKED | (1 << 8), String::new());
+        let constraint = libsql::Error::SqliteFailure(19, "UNIQUE constraint failed".into());
+
+        assert_eq!(transient(&busy), Some(Transient::Rejected));
+        assert_eq!(transient(&locked), Some(Transient::Rejected));
+        assert_eq!(transient(&constraint), None);
+    }
+
+    #[test]
+    fn remote_errors_are_classified() {
+        let cases = [
+            (
+                remote("status=503, body=unavailable"),
+                Some(Transient::Unreachable),
+            ),
+            (remote("status=400, body=bad request"), None),
+            (
+                remote("http error: connection reset"),
+                Some(Transient::Unreachable),
+            ),
+            (
+                remote("stream error: code: \"SQLITE_BUSY\""),
+                Some(Transient::Rejected),
+            ),
+            (remote("stream error: code: \"SQLITE_ERROR\""), None),
+            (
+                libsql::Error::ConnectionFailed("refused".into()),
+                Some(Transient::Unreachable),
+            ),
+            (libsql::Error::Misuse("oops".into()), None),
+        ];
+
+        for (err, expected) in cases {
+            assert_eq!(transient(&err), expected, "{}", err);
+        }
+    }
+
+    #[test]
+    fn transport_sources_are_unreachable() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let err = libsql::Error::Hrana(Box::new(io));
+
+        assert_eq!(transient(&err), Some(Transient::Unreachable));
+    }
+
+    #[test]
+    fn commit_is_only_retried_when_refused() {
+        let refused = DbError::Commit(libsql::Error::SqliteFailure(SQLITE_BUSY, String::new()));
+        let unreachable = DbError::Commit(remote("status=502, body=bad gateway"));
+
+        assert_eq!(refused.transient(), Some(Transient::Rejected));
+        assert_eq!(unreachable.transient(), None);
+        assert_eq!(DbError::PoolTimeout(Duration::ZERO).transient(), None);
+    }
+
+    #[test]
+    fn only_unreachable_sqld_trips_the_breaker() {
+        let cases = [
+            (remote("status=503, body=unavailable"), true),
+            (libsql::Error::ConnectionFailed("refused".into()), true),
+            (remote("stream error: code: \"SQLITE_BUSY\""), false),
+            (remote("stream error: code: \"SQLITE_CONSTRAINT\""), false),
+            (remote("stream error: near \"SELEC\": syntax error"), false),
+            (remote("status=400, body=bad request"), false),
+            (
+                libsql::Error::SqliteFailure(SQLITE_LOCKED, String::new()),
+                false,
+            ),
+        ];
+
+        for (err, expected) in cases {
+            let message = err.to_string();
+            assert_eq!(
+                DbError::Libsql(err).trips_breaker(),
+                expected,
+                "{}",
+                message
+            );
+        }
+        let commit = DbError::Commit(remote("status=502, body=bad gateway"));
+        assert!(commit.trips_breaker());
+        assert!(!DbError::PoolTimeout(Duration::ZERO).trips_breaker());
+    }
+
+    async fn settle(pool: &Pool) {
+        while pool.retiring.load(Ordering::Relaxed) > 0 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn shrinking_retires_idle_permits() {
+        let pool = Arc::new(Pool::new(Duration::ZERO, 4));
+
+        pool.resize(2);
+        settle(&pool).await;
+
+        assert_eq!(pool.semaphore.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn growing_cancels_pending_retirements() {
+        let pool = Arc::new(Pool::new(Duration::ZERO, 4));
+        let in_use = pool.semaphore.clone().acquire_many_owned(4).await.unwrap();
+
+        pool.resize(1);
+        pool.resize(3);
+        assert_eq!(pool.retiring.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.semaphore.available_permits(), 0);
+
+        drop(in_use);
+        settle(&pool).await;
+        assert_eq!(pool.semaphore.available_permits(), 3);
+
+        pool.resize(5);
+        assert_eq!(pool.semaphore.available_permits(), 5);
+    }
+
+    #[test]
+    fn shape_hides_literals() {
+        let insert = "INSERT INTO \"transactions\" (\"client_id\", \"amount\", \"operation\", \"description\") VALUES (1, 1500, 'd', 'it''s mine')";
+        let update = "UPDATE \"clients\" SET \"balance\" = -250 WHERE \"id\" = 42";
+
+        assert_eq!(
+            sql_shape(insert),
+            "INSERT INTO \"transactions\" (\"client_id\", \"amount\", \"operation\", \"description\") VALUES (?, ?, ?, ?)"
+        );
+        assert_eq!(
+            sql_shape(update),
+            "UPDATE \"clients\" SET \"balance\" = -? WHERE \"id\" = ?"
+        );
+    }
+
+    #[test]
+    fn shape_keeps_identifiers() {
+        assert_eq!(
+            sql_shape(
+                "SELECT t1.col2 FROM table_3 t1 WHERE x = 1.5e10 AND y = 0xFF AND z = x'00AB'"
+            ),
+            "SELECT t1.col2 FROM table_3 t1 WHERE x = ? AND y = ? AND z = ?"
+        );
+    }
+
+    #[test]
+    fn shape_drops_comments() {
+        assert_eq!(
+            sql_shape(
+                "/* request_id=a-1 */ DELETE FROM t WHERE id = 7; /* request_id=b-2 */ SELECT 1"
+            ),
+            "DELETE FROM t WHERE id = ?; SELECT ?"
+        );
+    }
+
+    #[test]
+    fn cause_is_found_in_the_chain() {
+        let err = anyhow::Error::new(DbError::Libsql(remote("status=503, body=")))
+            .context("failed to query for rows");
+
+        assert_eq!(transient_cause(&err), Some(Transient::Unreachable));
+        assert_eq!(transient_cause(&anyhow::anyhow!("parse error")), None);
     }
 }
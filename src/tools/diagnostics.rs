@@ -0,0 +1,223 @@
+//! Process and runtime metrics plus on-demand CPU profiles, only built with the `diagnostics`
+//! feature. Worker and queue metrics of the tokio runtime also need `--cfg tokio_unstable` in
+//! `RUSTFLAGS`.
+
+use std::time::Duration;
+
+use axum::extract::Query;
+use axum::http::header::CONTENT_TYPE;
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use prometheus::core::{Collector, Desc};
+use prometheus::process_collector::ProcessCollector;
+use prometheus::proto::MetricFamily;
+use prometheus::IntGauge;
+#[cfg(tokio_unstable)]
+use prometheus::{GaugeVec, IntGaugeVec, Opts};
+use serde::Deserialize;
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
+
+use crate::state::State;
+use crate::tools::auth;
+use crate::tools::error::CustomError;
+
+const MAX_PROFILE_DURATION: Duration = Duration::from_secs(60);
+const PROFILE_FREQUENCY: i32 = 99;
+
+// profiling is process wide, so only one profile runs at a time
+static PROFILING: Mutex<()> = Mutex::const_new(());
+
+pub fn register(registry: &prometheus::Registry) -> prometheus::Result<()> {
+    registry.register(Box::new(ProcessCollector::for_self()))?;
+    registry.register(Box::new(RuntimeCollector::new(Handle::current())))
+}
+
+/// admin only routes under `/admin/diagnostics`
+pub fn router(state: State) -> Router<State> {
+    Router::new()
+        .route("/admin/diagnostics/profile", get(profile))
+        .route_layer(middleware::from_fn_with_state(state, auth::require_admin))
+}
+
+/// reads the metrics of the tokio runtime every time it is scraped
+struct RuntimeCollector {
+    handle: Handle,
+    workers: IntGauge,
+
+    #[cfg(tokio_unstable)]
+    blocking_threads: IntGauge,
+    #[cfg(tokio_unstable)]
+    injection_queue_depth: IntGauge,
+    #[cfg(tokio_unstable)]
+    worker_busy: GaugeVec,
+    #[cfg(tokio_unstable)]
+    worker_queue_depth: IntGaugeVec,
+}
+
+impl RuntimeCollector {
+    fn new(handle: Handle) -> Self {
+        Self {
+            handle,
+            workers: IntGauge::new("tokio_workers", "Worker threads of the runtime").unwrap(),
+
+            #[cfg(tokio_unstable)]
+            blocking_threads: IntGauge::new(
+                "tokio_blocking_threads",
+                "Threads spawned for blocking tasks",
+            )
+            .unwrap(),
+            #[cfg(tokio_unstable)]
+            injection_queue_depth: IntGauge::new(
+                "tokio_injection_queue_depth",
+                "Tasks waiting in the global queue",
+            )
+            .unwrap(),
+            #[cfg(tokio_unstable)]
+            worker_busy: GaugeVec::new(
+                Opts::new(
+                    "tokio_worker_busy_seconds",
+                    "Time each worker spent running tasks since startup (uses seconds)",
+                ),
+                &["worker"],
+            )
+            .unwrap(),
+            #[cfg(tokio_unstable)]
+            worker_queue_depth: IntGaugeVec::new(
+                Opts::new(
+                    "tokio_worker_local_queue_depth",
+                    "Tasks waiting in the local queue of each worker",
+                ),
+                &["worker"],
+            )
+            .unwrap(),
+        }
+    }
+
+    fn collectors(&self) -> Vec<&dyn Collector> {
+        #[allow(unused_mut)]
+        let mut collectors: Vec<&dyn Collector> = vec![&self.workers];
+
+        #[cfg(tokio_unstable)]
+        collectors.extend([
+            &self.blocking_threads as &dyn Collector,
+            &self.injection_queue_depth,
+            &self.worker_busy,
+            &self.worker_queue_depth,
+        ]);
+
+        collectors
+    }
+
+    fn update(&self) {
+        let metrics = self.handle.metrics();
+        self.workers.set(metrics.num_workers() as i64);
+
+        #[cfg(tokio_unstable)]
+        {
+            self.blocking_threads
+                .set(metrics.num_blocking_threads() as i64);
+            self.injection_queue_depth
+                .set(metrics.injection_queue_depth() as i64);
+
+            for worker in 0..metrics.num_workers() {
+                let label = worker.to_string();
+                self.worker_busy
+                    .with_label_values(&[&label])
+                    .set(metrics.worker_total_busy_duration(worker).as_secs_f64());
+                self.worker_queue_depth
+                    .with_label_values(&[&label])
+                    .set(metrics.worker_local_queue_depth(worker) as i64);
+            }
+        }
+    }
+}
+
+impl Collector for RuntimeCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.collectors()
+            .into_iter()
+            .flat_map(|collector| collector.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.update();
+
+        self.collectors()
+            .into_iter()
+            .flat_map(|collector| collector.collect())
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileFormat {
+    /// SVG, to be opened in a browser
+    #[default]
+    Flamegraph,
+    /// protobuf, for `go tool pprof` and friends
+    Pprof,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileParams {
+    #[serde(default = "default_profile_seconds")]
+    seconds: u64,
+
+    #[serde(default)]
+    format: ProfileFormat,
+}
+
+fn default_profile_seconds() -> u64 {
+    10
+}
+
+/// samples the CPU for `seconds` and returns a flamegraph or a pprof profile
+pub async fn profile(Query(params): Query<ProfileParams>) -> Result<Response, CustomError> {
+    let Ok(_running) = PROFILING.try_lock() else {
+        return Err(CustomError::Unavailable(
+            "a profile is already being taken".into(),
+        ));
+    };
+
+    let duration = Duration::from_secs(params.seconds).min(MAX_PROFILE_DURATION);
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(PROFILE_FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|err| anyhow::anyhow!("failed to start the profiler: {}", err))?;
+
+    tracing::info!("Profiling the CPU for {:?}", duration);
+    tokio::time::sleep(duration).await;
+
+    let report = guard
+        .report()
+        .build()
+        .map_err(|err| anyhow::anyhow!("failed to build the profile: {}", err))?;
+
+    let mut body = Vec::new();
+    let content_type = match params.format {
+        ProfileFormat::Flamegraph => {
+            report
+                .flamegraph(&mut body)
+                .map_err(|err| anyhow::anyhow!("failed to render the flamegraph: {}", err))?;
+            "image/svg+xml"
+        }
+        ProfileFormat::Pprof => {
+            use pprof::protos::Message;
+
+            report
+                .pprof()
+                .map_err(|err| anyhow::anyhow!("failed to build the pprof profile: {}", err))?
+                .encode(&mut body)
+                .map_err(|err| anyhow::anyhow!("failed to encode the pprof profile: {}", err))?;
+            "application/octet-stream"
+        }
+    };
+
+    Ok(([(CONTENT_TYPE, content_type)], body).into_response())
+}
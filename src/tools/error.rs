@@ -2,17 +2,18 @@ use std::any::Any;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 
-use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
-use axum::Json;
 use derive_new::new;
-use serde::Serialize;
-use validify::ValidationErrors;
+use validify::{ValidationError, ValidationErrors};
+
+use crate::tools::db::DbError;
+use crate::tools::metrics::PANICS_COUNTER;
+use crate::tools::problem::{ErrorCode, Problem, Reason};
 
 #[derive(Debug, thiserror::Error)]
 pub enum CustomError {
     #[error("{0}")]
-    Unexpected(#[from] anyhow::Error),
+    Unexpected(anyhow::Error),
 
     #[error("{0}")]
     Validation(#[from] ValidationErrors),
@@ -21,21 +22,62 @@ pub enum CustomError {
     Domain(#[from] DomainError),
 
     #[error("request is invalid: {0}")]
-    Rejection(String),
+    Rejection(RejectionError),
+
+    #[error("service unavailable: {0}")]
+    Unavailable(String),
+}
+
+impl From<anyhow::Error> for CustomError {
+    /// a saturated or unreachable database is reported as unavailable rather than as a failure
+    fn from(err: anyhow::Error) -> Self {
+        let deadline = err
+            .chain()
+            .find_map(|cause| match cause.downcast_ref::<DbError>() {
+                Some(DbError::Deadline(exceeded)) => Some(*exceeded),
+                _ => None,
+            });
+        if let Some(exceeded) = deadline {
+            return exceeded.into();
+        }
+
+        let overloaded = err.chain().any(|cause| {
+            cause
+                .downcast_ref::<DbError>()
+                .is_some_and(DbError::is_unavailable)
+        });
 
-    #[error("lock is being held: {0}")]
-    LockHeld(String),
+        if overloaded {
+            CustomError::Unavailable(format!("{:#}", err))
+        } else {
+            CustomError::Unexpected(err)
+        }
+    }
 }
 
-#[derive(new, Debug, Serialize)]
+#[derive(new, Debug)]
 pub struct DomainError {
+    pub code: ErrorCode,
+    /// english message for the logs, callers get the localized one from the catalog
     pub message: String,
-    pub status: u16,
+
+    #[new(default)]
+    pub params: Vec<(&'static str, String)>,
 }
 
 impl DomainError {
+    pub fn with_param(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.params.push((name, value.to_string()));
+        self
+    }
+
     pub fn pretty(&self) -> String {
-        format!("[{}] - {}", self.status, self.message)
+        format!(
+            "[{}] {} - {}",
+            self.code.status().as_u16(),
+            self.code.as_str(),
+            self.message
+        )
     }
 }
 
@@ -51,56 +93,97 @@ impl Error for DomainError {
     }
 }
 
-/// logs out and returns an error json payload for custom errors
+/// request that could not be extracted (malformed path or body), along with the offending
+/// field when it is known. `detail` is only logged, callers get the message of `reason`.
+#[derive(new, Debug)]
+pub struct RejectionError {
+    pub code: ErrorCode,
+    pub reason: Reason,
+    pub field: Option<String>,
+    pub detail: String,
+}
+
+impl Display for RejectionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "[{}] - {}: {}", self.code.as_str(), field, self.detail),
+            None => write!(f, "[{}] - {}", self.code.as_str(), self.detail),
+        }
+    }
+}
+
+/// logs out and returns an `application/problem+json` payload for custom errors
 impl IntoResponse for CustomError {
     fn into_response(self) -> Response {
-        match self {
+        let problem = match self {
             CustomError::Unexpected(err) => {
                 tracing::error!("unexpected error: {} - {}", err, err.root_cause());
 
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    r#"{ "code": "unexpected", "message": "erro inesperado", "status": 500 }"#,
-                )
-                    .into_response()
+                // internals are not leaked to callers
+                Problem::new(ErrorCode::Unexpected, String::new())
             }
 
             CustomError::Validation(err) => {
                 tracing::warn!("{}", err);
 
-                (StatusCode::UNPROCESSABLE_ENTITY, Json(err)).into_response()
+                let errors = serde_json::to_value(&err).unwrap_or_default();
+                let (reason, field) = validation_reason(&err);
+
+                Problem::new(ErrorCode::ValidationFailed, err.to_string())
+                    .with_errors(errors)
+                    .with_reason(reason)
+                    .with_field(field)
             }
 
             CustomError::Domain(err) => {
                 tracing::warn!("domain error: {}", err.pretty());
 
-                (StatusCode::from_u16(err.status).unwrap(), Json(err)).into_response()
+                Problem::new(err.code, err.message).with_params(err.params)
             }
 
             CustomError::Rejection(err) => {
-                tracing::warn!("{}", err);
+                tracing::warn!("rejected request: {}", err);
 
-                (
-                    StatusCode::UNPROCESSABLE_ENTITY,
-                    r#"{ "code": "unproc", "message": "requisição inválida", "status": 422 }"#,
-                )
-                    .into_response()
+                Problem::new(err.code, err.detail)
+                    .with_reason(err.reason)
+                    .with_field(err.field)
             }
 
-            CustomError::LockHeld(err) => {
-                tracing::warn!(err);
+            CustomError::Unavailable(err) => {
+                tracing::warn!("service unavailable: {}", err);
 
-                (
-                    StatusCode::LOCKED,
-                    r#"{ "code": "lockheld", "message": "recurso indisponível", "status": 409 }"#,
-                )
-                    .into_response()
+                Problem::new(ErrorCode::Unavailable, err)
             }
-        }
+        };
+
+        problem.into_response()
     }
 }
 
+/// the detail describes the first field that failed, `errors` lists all of them
+fn validation_reason(err: &ValidationErrors) -> (Reason, Option<String>) {
+    let failed = err.errors().iter().find_map(|error| match error {
+        ValidationError::Field { .. } => Some((error.code(), error.field_name()?)),
+        ValidationError::Schema { .. } => None,
+    });
+
+    let Some((code, field)) = failed else {
+        return (Reason::InvalidData, None);
+    };
+
+    let reason = match code {
+        "range" => Reason::OutOfRange,
+        "length" => Reason::InvalidLength,
+        "in" | "is_in" => Reason::NotAllowed,
+        _ => Reason::InvalidValue,
+    };
+
+    (reason, Some(field.to_string()))
+}
+
 pub fn handle_panic(err: Box<dyn Any + Send + 'static>) -> Response {
+    PANICS_COUNTER.inc();
+
     let error_message = match err.downcast_ref::<String>() {
         Some(error) => format!("Panic occurred: {}", error),
         None => match err.downcast_ref::<&str>() {
@@ -0,0 +1,309 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::async_trait;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::config::app_config::AppConfig;
+use crate::tools::breaker::BreakerState;
+use crate::tools::db::PooledLibsqlDatabase;
+use crate::tools::locker::Locker;
+use crate::tools::shutdown::Shutdown;
+
+/// a dependency readiness is checked against
+#[async_trait]
+pub trait Probe: Send + Sync {
+    async fn ping(&self) -> anyhow::Result<()>;
+
+    fn breaker_state(&self) -> BreakerState;
+}
+
+#[async_trait]
+impl Probe for PooledLibsqlDatabase {
+    async fn ping(&self) -> anyhow::Result<()> {
+        PooledLibsqlDatabase::ping(self).await
+    }
+
+    fn breaker_state(&self) -> BreakerState {
+        PooledLibsqlDatabase::breaker_state(self)
+    }
+}
+
+#[async_trait]
+impl Probe for Locker {
+    async fn ping(&self) -> anyhow::Result<()> {
+        Locker::ping(self).await
+    }
+
+    fn breaker_state(&self) -> BreakerState {
+        Locker::breaker_state(self)
+    }
+}
+
+/// Probes the dependencies an instance needs to serve traffic. Results are cached for a short
+/// while so that frequent health checks don't add load to sqld and redis.
+pub struct Readiness {
+    db: Arc<dyn Probe>,
+    locker: Arc<dyn Probe>,
+    shutdown: Arc<Shutdown>,
+    // without it, locked writes fall back to unlocked ones rather than failing
+    locker_required: bool,
+    cache_ttl: Duration,
+    timeout: Duration,
+    cached: Mutex<Option<(Instant, Vec<DependencyStatus>)>>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    /// an optional dependency is down, traffic is still served
+    Degraded,
+    Down,
+    Draining,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ReadinessReport {
+    pub status: Status,
+    pub dependencies: Vec<DependencyStatus>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DependencyStatus {
+    pub name: &'static str,
+    pub status: Status,
+    pub latency_ms: f64,
+
+    /// state of the circuit breaker in front of the dependency, after the probe
+    pub breaker: BreakerState,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Readiness {
+    pub fn new(
+        conf: &AppConfig,
+        db: Arc<dyn Probe>,
+        locker: Arc<dyn Probe>,
+        shutdown: Arc<Shutdown>,
+    ) -> Self {
+        Self {
+            db,
+            locker,
+            shutdown,
+            locker_required: !conf.redis.fallback,
+            cache_ttl: conf.health.cache_ttl,
+            timeout: conf.health.timeout,
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub async fn report(&self) -> ReadinessReport {
+        let dependencies = self.dependencies().await;
+
+        let status = if self.shutdown.is_draining() {
+            Status::Draining
+        } else if dependencies.iter().any(|d| d.status == Status::Down) {
+            Status::Down
+        } else if dependencies.iter().any(|d| d.status == Status::Degraded) {
+            Status::Degraded
+        } else {
+            Status::Up
+        };
+
+        ReadinessReport {
+            status,
+            dependencies,
+        }
+    }
+
+    async fn dependencies(&self) -> Vec<DependencyStatus> {
+        // holding the lock while probing makes concurrent checks share a single probe
+        let mut cached = self.cached.lock().await;
+
+        if let Some((probed_at, dependencies)) = &*cached {
+            if probed_at.elapsed() < self.cache_ttl {
+                return dependencies.clone();
+            }
+        }
+
+        let (db, locker) = tokio::join!(
+            self.probe("database", true, self.db.ping(), || self.db.breaker_state()),
+            self.probe("locker", self.locker_required, self.locker.ping(), || self
+                .locker
+                .breaker_state()),
+        );
+
+        let dependencies = vec![db, locker];
+        *cached = Some((Instant::now(), dependencies.clone()));
+
+        dependencies
+    }
+
+    async fn probe<F>(
+        &self,
+        name: &'static str,
+        required: bool,
+        check: F,
+        breaker: impl Fn() -> BreakerState,
+    ) -> DependencyStatus
+    where
+        F: Future<Output = anyhow::Result<()>>,
+    {
+        let start = Instant::now();
+        let result = tokio::time::timeout(self.timeout, check).await;
+        let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        let error = match result {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(format!("{:#}", err)),
+            Err(_) => Some(format!("timed out after {:?}", self.timeout)),
+        };
+
+        if let Some(err) = &error {
+            tracing::warn!("Readiness probe for {} failed: {}", name, err);
+        }
+
+        DependencyStatus {
+            name,
+            status: match (&error, required) {
+                (None, _) => Status::Up,
+                (Some(_), true) => Status::Down,
+                (Some(_), false) => Status::Degraded,
+            },
+            latency_ms,
+            breaker: breaker(),
+            error,
+        }
+    }
+}
+
+/// the process is up, regardless of its dependencies
+pub async fn live() -> impl IntoResponse {
+    "OK"
+}
+
+/// used by haproxy, only routes traffic here while the dependencies it can't serve without are
+/// reachable
+pub async fn ready(State(readiness): State<Arc<Readiness>>) -> impl IntoResponse {
+    let report = readiness.report().await;
+
+    let status = match report.status {
+        Status::Up | Status::Degraded => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (status, Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Stub {
+        down: AtomicBool,
+        pings: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Probe for Stub {
+        async fn ping(&self) -> anyhow::Result<()> {
+            self.pings.fetch_add(1, Ordering::Relaxed);
+            if self.down.load(Ordering::Relaxed) {
+                anyhow::bail!("connection refused");
+            }
+
+            Ok(())
+        }
+
+        fn breaker_state(&self) -> BreakerState {
+            BreakerState::Closed
+        }
+    }
+
+    fn readiness(db: &Arc<Stub>, locker: &Arc<Stub>, locker_required: bool) -> Readiness {
+        Readiness {
+            db: db.clone(),
+            locker: locker.clone(),
+            shutdown: Arc::new(Shutdown::default()),
+            locker_required,
+            cache_ttl: Duration::ZERO,
+            timeout: Duration::from_secs(1),
+            cached: Mutex::new(None),
+        }
+    }
+
+    fn down() -> Arc<Stub> {
+        let stub = Stub::default();
+        stub.down.store(true, Ordering::Relaxed);
+        Arc::new(stub)
+    }
+
+    async fn status(readiness: Readiness) -> (Status, StatusCode) {
+        let readiness = Arc::new(readiness);
+        let report = readiness.report().await;
+        let response = ready(State(readiness)).await.into_response();
+
+        (report.status, response.status())
+    }
+
+    #[tokio::test]
+    async fn up_when_every_dependency_is() {
+        let up = Arc::new(Stub::default());
+
+        assert_eq!(
+            status(readiness(&up, &up, true)).await,
+            (Status::Up, StatusCode::OK)
+        );
+    }
+
+    #[tokio::test]
+    async fn degraded_without_an_optional_locker() {
+        let readiness = readiness(&Arc::default(), &down(), false);
+
+        assert_eq!(status(readiness).await, (Status::Degraded, StatusCode::OK));
+    }
+
+    #[tokio::test]
+    async fn down_without_a_required_dependency() {
+        let locker_down = readiness(&Arc::default(), &down(), true);
+        let db_down = readiness(&down(), &Arc::default(), false);
+
+        assert_eq!(
+            status(locker_down).await,
+            (Status::Down, StatusCode::SERVICE_UNAVAILABLE)
+        );
+        assert_eq!(
+            status(db_down).await,
+            (Status::Down, StatusCode::SERVICE_UNAVAILABLE)
+        );
+    }
+
+    #[tokio::test]
+    async fn reuses_probes_within_the_cache_ttl() {
+        let db = Arc::new(Stub::default());
+        let mut cached = readiness(&db, &Arc::default(), true);
+        cached.cache_ttl = Duration::from_secs(60);
+
+        cached.report().await;
+        db.down.store(true, Ordering::Relaxed);
+        assert_eq!(cached.report().await.status, Status::Up);
+        assert_eq!(db.pings.load(Ordering::Relaxed), 1);
+
+        let uncached = readiness(&db, &Arc::default(), true);
+        uncached.report().await;
+        assert_eq!(uncached.report().await.status, Status::Down);
+        assert_eq!(db.pings.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,285 @@
+use axum::http::header::ACCEPT_LANGUAGE;
+use axum::http::HeaderMap;
+
+use crate::tools::problem::{ErrorCode, Reason};
+
+/// Languages user-facing messages are available in. Portuguese is the language of the API
+/// and the fallback whenever the caller doesn't ask for anything we support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    PtBr,
+    En,
+}
+
+impl Locale {
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Locale::PtBr => "pt-BR",
+            Locale::En => "en",
+        }
+    }
+
+    /// picks the supported language with the highest quality from `Accept-Language`
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let Some(header) = headers.get(ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok()) else {
+            return Locale::default();
+        };
+
+        let mut ranges = header
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim().to_ascii_lowercase();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+
+                Some((tag, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect::<Vec<_>>();
+
+        // stable sort keeps the header order between equal qualities
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges
+            .iter()
+            .find_map(|(tag, _)| match tag.split('-').next() {
+                Some("pt") | Some("*") => Some(Locale::PtBr),
+                Some("en") => Some(Locale::En),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+}
+
+struct Message {
+    title: &'static str,
+    // errors without a detail template keep the detail they were raised with
+    detail: Option<&'static str>,
+}
+
+fn message(code: ErrorCode, locale: Locale) -> Message {
+    use ErrorCode::*;
+    use Locale::*;
+
+    let (title, detail) = match (code, locale) {
+        (Unexpected, PtBr) => ("erro inesperado", Some("ocorreu um erro inesperado")),
+        (Unexpected, En) => ("unexpected error", Some("an unexpected error occurred")),
+
+        (InvalidRequest, PtBr) => ("requisição inválida", None),
+        (InvalidRequest, En) => ("invalid request", None),
+
+        (InvalidBody, PtBr) => ("requisição inválida", None),
+        (InvalidBody, En) => ("invalid request body", None),
+
+        (UnsupportedMediaType, PtBr) => ("tipo de conteúdo não suportado", None),
+        (UnsupportedMediaType, En) => ("unsupported media type", None),
+
+        (ValidationFailed, PtBr) => ("dados inválidos", None),
+        (ValidationFailed, En) => ("invalid data", None),
+
+        (ClientNotFound, PtBr) => (
+            "cliente não encontrado",
+            Some("cliente {client_id} não encontrado"),
+        ),
+        (ClientNotFound, En) => ("client not found", Some("client {client_id} not found")),
+
+        (InsufficientFunds, PtBr) => (
+            "saldo insuficiente",
+            Some("saldo insuficiente para o cliente {client_id}"),
+        ),
+        (InsufficientFunds, En) => (
+            "insufficient funds",
+            Some("insufficient funds for client {client_id}"),
+        ),
+
+        (Unauthenticated, PtBr) => ("não autenticado", Some("credenciais ausentes ou inválidas")),
+        (Unauthenticated, En) => ("unauthenticated", Some("missing or invalid credentials")),
+
+        (Forbidden, PtBr) => (
+            "acesso negado",
+            Some("sem permissão para acessar este recurso"),
+        ),
+        (Forbidden, En) => ("forbidden", Some("not allowed to access this resource")),
+
+        (RateLimited, PtBr) => (
+            "limite de requisições excedido",
+            Some("limite excedido para o cliente {client_id}, tente novamente em {retry_after}s"),
+        ),
+        (RateLimited, En) => (
+            "too many requests",
+            Some("rate limit exceeded for client {client_id}, retry in {retry_after}s"),
+        ),
+
+        (Unavailable, PtBr) => (
+            "serviço indisponível",
+            Some("o serviço está sobrecarregado, tente novamente"),
+        ),
+        (Unavailable, En) => (
+            "service unavailable",
+            Some("the service is overloaded, try again"),
+        ),
+
+        (Timeout, PtBr) => (
+            "tempo esgotado",
+            Some("a requisição não foi concluída em {deadline_ms}ms"),
+        ),
+        (Timeout, En) => (
+            "timed out",
+            Some("the request did not complete within {deadline_ms}ms"),
+        ),
+    };
+
+    Message { title, detail }
+}
+
+fn reason(reason: Reason, locale: Locale) -> &'static str {
+    use Locale::*;
+    use Reason::*;
+
+    match (reason, locale) {
+        (InvalidParam, PtBr) => "valor inválido para o parâmetro {field}",
+        (InvalidParam, En) => "invalid value for the {field} parameter",
+
+        (InvalidPath, PtBr) => "caminho da requisição inválido",
+        (InvalidPath, En) => "invalid request path",
+
+        (UnsupportedContentType, PtBr) => "o corpo deve ser enviado como application/json",
+        (UnsupportedContentType, En) => "the body must be sent as application/json",
+
+        (UnreadableBody, PtBr) => "não foi possível ler o corpo da requisição",
+        (UnreadableBody, En) => "the request body could not be read",
+
+        (MalformedJson, PtBr) => "o corpo da requisição não é um json válido",
+        (MalformedJson, En) => "the request body is not valid json",
+
+        (InvalidField, PtBr) => "valor ou tipo inválido para o campo {field}",
+        (InvalidField, En) => "invalid value or type for the {field} field",
+
+        (InvalidShape, PtBr) => "o corpo da requisição não tem os campos esperados",
+        (InvalidShape, En) => "the request body doesn't have the expected fields",
+
+        (OutOfRange, PtBr) => "o campo {field} está fora do intervalo permitido",
+        (OutOfRange, En) => "the {field} field is out of the allowed range",
+
+        (InvalidLength, PtBr) => "o campo {field} não tem um tamanho permitido",
+        (InvalidLength, En) => "the {field} field doesn't have an allowed length",
+
+        (NotAllowed, PtBr) => "o campo {field} não tem um dos valores permitidos",
+        (NotAllowed, En) => "the {field} field isn't one of the allowed values",
+
+        (InvalidValue, PtBr) => "o campo {field} é inválido",
+        (InvalidValue, En) => "the {field} field is invalid",
+
+        (InvalidData, PtBr) => "os dados enviados são inválidos",
+        (InvalidData, En) => "the data sent is invalid",
+    }
+}
+
+pub fn title(code: ErrorCode, locale: Locale) -> &'static str {
+    message(code, locale).title
+}
+
+/// renders the detail template of `code`, or else of `reason`, replacing `{name}` placeholders
+/// with `params`
+pub fn detail(
+    code: ErrorCode,
+    reason: Option<Reason>,
+    locale: Locale,
+    params: &[(&'static str, String)],
+) -> Option<String> {
+    let template = message(code, locale)
+        .detail
+        .or_else(|| reason.map(|reason| self::reason(reason, locale)))?;
+
+    let detail = params
+        .iter()
+        .fold(template.to_string(), |detail, (name, value)| {
+            detail.replace(&format!("{{{}}}", name), value)
+        });
+
+    Some(detail)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn locale(accept_language: &str) -> Locale {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT_LANGUAGE,
+            HeaderValue::from_str(accept_language).unwrap(),
+        );
+
+        Locale::from_headers(&headers)
+    }
+
+    #[test]
+    fn defaults_to_portuguese() {
+        assert_eq!(Locale::from_headers(&HeaderMap::new()), Locale::PtBr);
+        assert_eq!(locale("fr-FR, de"), Locale::PtBr);
+        assert_eq!(locale("*"), Locale::PtBr);
+    }
+
+    #[test]
+    fn picks_the_highest_quality() {
+        assert_eq!(locale("en-US"), Locale::En);
+        assert_eq!(locale("pt-BR;q=0.5, en;q=0.8"), Locale::En);
+        assert_eq!(locale("fr;q=1.0, EN-gb;q=0.3, pt;q=0.2"), Locale::En);
+        assert_eq!(locale("en;q=0, pt"), Locale::PtBr);
+    }
+
+    #[test]
+    fn keeps_the_header_order_between_equal_qualities() {
+        assert_eq!(locale("en, pt-BR"), Locale::En);
+        assert_eq!(locale("pt, en"), Locale::PtBr);
+    }
+
+    #[test]
+    fn details_rejections_by_reason() {
+        let params = [("field", "valor".to_string())];
+
+        let pt = detail(
+            ErrorCode::InvalidBody,
+            Some(Reason::InvalidField),
+            Locale::PtBr,
+            &params,
+        );
+        let en = detail(
+            ErrorCode::InvalidBody,
+            Some(Reason::InvalidField),
+            Locale::En,
+            &params,
+        );
+
+        assert_eq!(pt.unwrap(), "valor ou tipo inválido para o campo valor");
+        assert_eq!(en.unwrap(), "invalid value or type for the valor field");
+    }
+
+    #[test]
+    fn keeps_the_detail_without_a_template() {
+        assert_eq!(
+            detail(ErrorCode::InvalidRequest, None, Locale::En, &[]),
+            None
+        );
+    }
+
+    #[test]
+    fn prefers_the_template_of_the_code() {
+        let params = [("client_id", "6".to_string())];
+        let detail = detail(
+            ErrorCode::ClientNotFound,
+            Some(Reason::InvalidData),
+            Locale::En,
+            &params,
+        );
+
+        assert_eq!(detail.unwrap(), "client 6 not found");
+    }
+}
@@ -1,59 +1,291 @@
+use std::collections::hash_map::DefaultHasher;
 use std::future::Future;
-use std::time::Duration;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use redis::RedisError;
-use redlock::{RedLock, RedLockGuard};
+use redis::{RedisError, RedisResult, Script};
+use tracing::Instrument;
+use uuid::Uuid;
 
 use crate::config::app_config::AppConfig;
+use crate::tools::breaker::{BreakerState, CircuitBreaker};
 use crate::tools::error::CustomError;
-use crate::tools::metrics::{DeferredObserve, OPS_HISTOGRAM};
+use crate::tools::metrics::{
+    DeferredObserve, GaugeGuard, LOCK_CONTENDED_COUNTER, LOCK_EXPIRED_COUNTER,
+    LOCK_FAILURES_COUNTER, LOCK_HOLD_HISTOGRAM, LOCK_INFLIGHT_GAUGE, LOCK_WAIT_HISTOGRAM,
+    OPS_HISTOGRAM, SHED_COUNTER,
+};
+use crate::tools::overload::{self, GaveUp};
+use crate::tools::redis_connection::SharedConnection;
+use crate::tools::request_id;
 
+// lock keys are spread over a fixed number of buckets to keep metric cardinality bounded
+const METRIC_BUCKETS: u64 = 16;
+
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+// pause between attempts to take a busy key
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+// only deletes the key while it still holds our token, a lock that expired and was taken by
+// someone else is left alone
+const RELEASE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+  return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+/// Lock on a single redis key, taken with `SET NX PX` and released with [`RELEASE_SCRIPT`].
+/// Every redis call is async, so waiting for it is bounded by `acquire_timeout` and the
+/// deadline of the request.
 pub struct Locker {
-    client: RedLock,
-    default_ttl: Duration,
+    connection: SharedConnection,
+    release_script: Script,
+    default_ttl_ms: AtomicU64,
+    acquire_timeout: Duration,
+    breaker: Arc<CircuitBreaker>,
 }
 
 impl Locker {
-    pub async fn new(conf: &AppConfig) -> Self {
+    pub async fn new(conf: &AppConfig) -> RedisResult<Self> {
         let addr = format!("redis://{}:{}/", conf.redis.host, conf.redis.port);
-        let default_ttl = conf.redis.ttl;
+        let default_ttl_ms = AtomicU64::new(conf.redis.ttl.as_millis() as u64);
 
-        let client = RedLock::new(vec![addr]);
+        let client = redis::Client::open(addr)?;
 
-        Self {
-            client,
-            default_ttl,
-        }
+        Ok(Self {
+            connection: SharedConnection::new(client, CONNECT_TIMEOUT),
+            release_script: Script::new(RELEASE_SCRIPT),
+            default_ttl_ms,
+            acquire_timeout: conf.redis.acquire_timeout,
+            breaker: Arc::new(CircuitBreaker::new("redis", &conf.breakers.redis)),
+        })
+    }
+
+    /// goes through the breaker too, so that readiness checks probe a half open breaker
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        let ticket = self.breaker.allow()?;
+        let result = tokio::time::timeout(PING_TIMEOUT, async {
+            let mut conn = self.connection.get().await?;
+            let pong = redis::cmd("PING").query_async::<_, String>(&mut conn).await;
+            if pong.is_err() {
+                self.connection.reset();
+            }
+            pong?;
+
+            anyhow::Ok(())
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("redis ping timed out")));
+
+        self.breaker.record(ticket, result.is_ok());
+        result
+    }
+
+    pub fn breaker_state(&self) -> BreakerState {
+        self.breaker.state()
+    }
+
+    /// only affects locks acquired from now on
+    pub fn set_default_ttl(&self, ttl: Duration) {
+        self.default_ttl_ms
+            .store(ttl.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn default_ttl(&self) -> Duration {
+        Duration::from_millis(self.default_ttl_ms.load(Ordering::Relaxed))
     }
 
-    pub async fn with_lock<F, Fut, T>(&self, key: String, f: F) -> Result<T, CustomError>
+    /// Runs `f` while holding the lock, or `fallback` with the reason when redis can't be reached
+    /// at all (breaker open or connection errors). Errors of `f` itself are returned as they are,
+    /// as is a lock not acquired within `acquire_timeout`: the key is busy, redis is not gone.
+    /// `f` is given the instant the lock expires at, at the earliest.
+    pub async fn with_lock_or_else<F, Fut, G, GFut, T>(
+        &self,
+        key: String,
+        f: F,
+        fallback: G,
+    ) -> Result<T, CustomError>
     where
-        F: FnOnce() -> Fut,
+        F: FnOnce(Instant) -> Fut,
         Fut: Future<Output = Result<T, CustomError>>,
+        G: FnOnce(CustomError) -> GFut,
+        GFut: Future<Output = Result<T, CustomError>>,
     {
-        let _deferred_observe = DeferredObserve::new(&OPS_HISTOGRAM, &["acquire_lock"]);
+        let bucket = Self::bucket(&key);
+        let bucket_label = [bucket.as_str()];
 
-        match self.try_lock(&key).await {
-            Ok(_lock) => {
-                // successfully obtained distributed lock
+        let inflight = LOCK_INFLIGHT_GAUGE.with_label_values(&bucket_label);
+        if inflight.get() > 0 {
+            LOCK_CONTENDED_COUNTER
+                .with_label_values(&bucket_label)
+                .inc();
+        }
+        let inflight = GaugeGuard::new(inflight);
 
-                f().await
-                // lock is implicitly dropped here (see Drop trait implementation for RedLockGuard)
+        let ticket = match self.breaker.allow() {
+            Ok(ticket) => ticket,
+            Err(err) => {
+                drop(inflight);
+                return fallback(CustomError::Unavailable(err.to_string())).await;
+            }
+        };
+
+        let ttl = self.default_ttl();
+        let wait_start = Instant::now();
+        // an abandoned acquisition can leave the key behind, but only until its ttl expires
+        let lock = overload::bounded(self.acquire_timeout, self.try_lock(&key, ttl)).await;
+        LOCK_WAIT_HISTOGRAM
+            .with_label_values(&bucket_label)
+            .observe(wait_start.elapsed().as_secs_f64());
+
+        // a contended key says nothing about redis health, the call is not counted
+        let lock = match lock {
+            Ok(lock) => lock,
+            Err(GaveUp::Limit) => {
+                self.breaker.forget(ticket);
+                LOCK_FAILURES_COUNTER
+                    .with_label_values(&["wait_timeout"])
+                    .inc();
+                SHED_COUNTER.with_label_values(&["lock"]).inc();
+
+                return Err(CustomError::Unavailable(format!(
+                    "lock {} not acquired within {:?}",
+                    key, self.acquire_timeout
+                )));
+            }
+            Err(GaveUp::Deadline(exceeded)) => {
+                self.breaker.forget(ticket);
+                return Err(exceeded.into());
+            }
+        };
+
+        self.breaker.record(ticket, lock.is_ok());
+
+        match lock {
+            Ok(token) => {
+                // the key was set after the wait started, it can't expire any sooner
+                let expires_at = wait_start + ttl;
+
+                let hold_start = Instant::now();
+                let result = f(expires_at).await;
+                let held = hold_start.elapsed();
+
+                LOCK_HOLD_HISTOGRAM
+                    .with_label_values(&bucket_label)
+                    .observe(held.as_secs_f64());
+
+                if held >= ttl {
+                    tracing::warn!("Lock {} expired while being held ({:?})", key, held);
+                    LOCK_EXPIRED_COUNTER.with_label_values(&bucket_label).inc();
+                }
+
+                // a lock left behind only delays the next writer until its ttl expires
+                if let Err(err) = self.unlock(&key, &token).await {
+                    tracing::warn!("Failed to release lock {}: {}", key, err);
+                }
+
+                result
             }
 
             Err(err) => {
-                // unexpected error like IO error, transport error, etc.
-                Err(CustomError::Unexpected(anyhow::Error::new(err)))
+                LOCK_FAILURES_COUNTER
+                    .with_label_values(&[Self::failure_cause(&err)])
+                    .inc();
+
+                if !Self::is_unreachable(&err) {
+                    return Err(CustomError::Unexpected(anyhow::Error::new(err)));
+                }
+
+                drop(inflight);
+                fallback(CustomError::Unavailable(err.to_string())).await
             }
         }
     }
 
-    async fn try_lock(&self, key: &str) -> Result<RedLockGuard, RedisError> {
-        let lock = self
-            .client
-            .acquire_async(key.as_bytes(), self.default_ttl.as_millis() as usize)
-            .await?;
+    /// keeps trying to set the key until it's free, returns the token it was set to
+    async fn try_lock(&self, key: &str, ttl: Duration) -> RedisResult<String> {
+        let _deferred_observe = DeferredObserve::new(&OPS_HISTOGRAM, &["acquire_lock"]);
+
+        // lets redis slowness be tied back to the request that was waiting on it
+        let span = tracing::info_span!(
+            "acquire_lock",
+            key,
+            request_id = request_id::current().as_deref().unwrap_or("-"),
+        );
 
-        Ok(lock)
+        let token = Uuid::new_v4().to_string();
+        async {
+            let mut conn = self.connection.get().await?;
+            loop {
+                let set: RedisResult<Option<String>> = redis::cmd("SET")
+                    .arg(key)
+                    .arg(&token)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(ttl.as_millis() as u64)
+                    .query_async(&mut conn)
+                    .await;
+
+                match set {
+                    Ok(Some(_)) => return Ok(token.clone()),
+                    Ok(None) => tokio::time::sleep(RETRY_DELAY).await,
+                    Err(err) => {
+                        self.connection.reset();
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn unlock(&self, key: &str, token: &str) -> RedisResult<()> {
+        let mut conn = self.connection.get().await?;
+        let released = self
+            .release_script
+            .key(key)
+            .arg(token)
+            .invoke_async::<_, i64>(&mut conn)
+            .await;
+
+        if released.is_err() {
+            self.connection.reset();
+        }
+        released.map(|_| ())
+    }
+
+    fn bucket(key: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        (hasher.finish() % METRIC_BUCKETS).to_string()
+    }
+
+    /// redis could not be talked to, as opposed to answering with an error
+    fn is_unreachable(err: &RedisError) -> bool {
+        err.is_io_error()
+            || err.is_timeout()
+            || err.is_connection_refusal()
+            || err.is_connection_dropped()
+    }
+
+    fn failure_cause(err: &RedisError) -> &'static str {
+        if err.is_timeout() {
+            "timeout"
+        } else if err.is_connection_refusal() {
+            "connection_refused"
+        } else if err.is_connection_dropped() {
+            "connection_dropped"
+        } else if err.is_io_error() {
+            "io"
+        } else {
+            "other"
+        }
     }
 }
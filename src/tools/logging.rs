@@ -0,0 +1,83 @@
+use std::io;
+
+use anyhow::Context;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriterExt};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+use crate::config::app_config::{self, AppConfig};
+use crate::tools::telemetry;
+
+pub const LOG_FORMATS: &[&str] = &["pretty", "json"];
+pub const LOG_ROTATIONS: &[&str] = &["minutely", "hourly", "daily", "never"];
+
+/// handle used to swap the log filter of a running instance
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// flushes buffered file logs when dropped, must be kept alive until the process exits
+pub type LogGuard = Option<WorkerGuard>;
+
+pub fn init(conf: &AppConfig) -> anyhow::Result<(LogHandle, LogGuard)> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(&conf.logging.filter)?);
+    let (writer, guard) = writer(&conf.logging)?;
+
+    // escape codes are only meant for terminals
+    let ansi = conf.logging.file.is_none();
+    let (json, pretty) = match conf.logging.format.as_str() {
+        "json" => (Some(fmt::layer().json().with_writer(writer)), None),
+        _ => (None, Some(fmt::layer().with_ansi(ansi).with_writer(writer))),
+    };
+
+    // spans are exported with the same filter as the logs
+    let otel = if conf.telemetry.enabled {
+        Some(tracing_opentelemetry::layer().with_tracer(telemetry::tracer(conf)?))
+    } else {
+        None
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json)
+        .with(pretty)
+        .with(otel)
+        .try_init()?;
+
+    Ok((handle, guard))
+}
+
+/// stdout, plus a rotated file written from a background thread when `logging.file` is set
+fn writer(conf: &app_config::Logging) -> anyhow::Result<(BoxMakeWriter, LogGuard)> {
+    let Some(file) = &conf.file else {
+        return Ok((BoxMakeWriter::new(io::stdout), None));
+    };
+
+    let rotation = match file.rotation.as_str() {
+        "minutely" => Rotation::MINUTELY,
+        "hourly" => Rotation::HOURLY,
+        "daily" => Rotation::DAILY,
+        _ => Rotation::NEVER,
+    };
+
+    let appender = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(&file.prefix)
+        .filename_suffix("log")
+        .max_log_files(file.max_files)
+        .build(&file.dir)
+        .with_context(|| format!("failed to open log files in {}", file.dir.display()))?;
+    let (non_blocking, guard) = tracing_appender::non_blocking(appender);
+
+    Ok((
+        BoxMakeWriter::new(io::stdout.and(non_blocking)),
+        Some(guard),
+    ))
+}
+
+pub fn set_filter(handle: &LogHandle, directives: &str) -> anyhow::Result<()> {
+    handle.reload(EnvFilter::try_new(directives)?)?;
+
+    Ok(())
+}
@@ -1,22 +1,248 @@
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::time::Instant;
 
-use axum::extract::State;
-use axum::response::IntoResponse;
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
 use lazy_static::lazy_static;
-use prometheus::{register_histogram_vec, HistogramVec};
+use prometheus::core::Collector;
+use prometheus::{
+    Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+};
+
+use crate::config::app_config::AppConfig;
+
+// Every metric is created unregistered and only added to the registry served on `/prometheus`
+// (see `register`), so nothing ends up in the prometheus default registry.
+
+/// Latency histogram whose buckets come from `metrics.buckets`: it only exists once `register`
+/// created it, since buckets can't change after the first observation.
+pub struct HistogramFamily {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    histograms: OnceLock<HistogramVec>,
+}
+
+impl HistogramFamily {
+    const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            histograms: OnceLock::new(),
+        }
+    }
+
+    fn create(&self, buckets: &[f64]) -> prometheus::Result<HistogramVec> {
+        let opts = HistogramOpts::new(self.name, self.help).buckets(buckets.to_vec());
+        let histograms = HistogramVec::new(opts, self.labels)?;
+        self.histograms
+            .set(histograms.clone())
+            .map_err(|_| prometheus::Error::AlreadyReg)?;
+
+        Ok(histograms)
+    }
+
+    pub fn with_label_values(&self, labels: &[&str]) -> Histogram {
+        self.histograms
+            .get()
+            .unwrap_or_else(|| panic!("histogram {} used before metrics::register", self.name))
+            .with_label_values(labels)
+    }
+}
+
+pub static OPS_HISTOGRAM: HistogramFamily = HistogramFamily::new(
+    "ops_duration_seconds",
+    "Performance histograms (uses seconds)",
+    &["operation"],
+);
+pub static SQL_HISTOGRAM: HistogramFamily = HistogramFamily::new(
+    "sql_duration_seconds",
+    "Time spent on database statements, by operation (uses seconds)",
+    &["operation"],
+);
+pub static HTTP_DURATION_HISTOGRAM: HistogramFamily = HistogramFamily::new(
+    "http_request_duration_seconds",
+    "Time to serve HTTP requests (uses seconds)",
+    &["method", "route", "status"],
+);
+pub static LOCK_WAIT_HISTOGRAM: HistogramFamily = HistogramFamily::new(
+    "lock_wait_seconds",
+    "Time spent waiting to acquire a distributed lock (uses seconds)",
+    &["bucket"],
+);
+pub static LOCK_HOLD_HISTOGRAM: HistogramFamily = HistogramFamily::new(
+    "lock_hold_seconds",
+    "Time a distributed lock was held (uses seconds)",
+    &["bucket"],
+);
+
+fn counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    IntCounterVec::new(Opts::new(name, help), labels).unwrap()
+}
+
+fn gauge_vec(name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+    IntGaugeVec::new(Opts::new(name, help), labels).unwrap()
+}
 
 lazy_static! {
-    pub static ref OPS_HISTOGRAM: HistogramVec = register_histogram_vec!(
-        "ops_duration_seconds",
-        "Performance histograms (uses seconds)",
+    pub static ref SLOW_QUERIES_COUNTER: IntCounterVec = counter_vec(
+        "slow_queries_total",
+        "Database statements slower than the slow query threshold, by operation",
+        &["operation"]
+    );
+    pub static ref HTTP_REQUESTS_COUNTER: IntCounterVec = counter_vec(
+        "http_requests_total",
+        "HTTP requests served, by route and status",
+        &["method", "route", "status"]
+    );
+    pub static ref PANICS_COUNTER: IntCounter =
+        IntCounter::new("panics_total", "Handlers that panicked").unwrap();
+    pub static ref TRANSACTIONS_COUNTER: IntCounterVec = counter_vec(
+        "transactions_total",
+        "Transactions persisted, by operation",
+        &["operation"]
+    );
+    pub static ref TRANSACTION_REJECTIONS_COUNTER: IntCounterVec = counter_vec(
+        "transaction_rejections_total",
+        "Transactions refused, by reason",
+        &["reason"]
+    );
+    pub static ref DB_RETRIES_COUNTER: IntCounterVec = counter_vec(
+        "db_retries_total",
+        "Database operations tried again after a transient error, by operation",
         &["operation"]
+    );
+    pub static ref DB_RETRIES_EXHAUSTED_COUNTER: IntCounterVec = counter_vec(
+        "db_retries_exhausted_total",
+        "Database operations that kept failing with transient errors, by operation",
+        &["operation"]
+    );
+    pub static ref DB_POOL_CONNECTIONS_GAUGE: IntGaugeVec = gauge_vec(
+        "db_pool_connections",
+        "Pooled database connections, by state",
+        &["state"]
+    );
+    pub static ref DB_POOL_WAITERS_GAUGE: IntGauge = IntGauge::new(
+        "db_pool_waiters",
+        "Requests waiting for a pooled database connection"
+    )
+    .unwrap();
+    pub static ref LOCK_FAILURES_COUNTER: IntCounterVec = counter_vec(
+        "lock_failures_total",
+        "Distributed lock acquisition failures",
+        &["cause"]
+    );
+    pub static ref LOCK_EXPIRED_COUNTER: IntCounterVec = counter_vec(
+        "lock_expired_total",
+        "Distributed locks whose ttl expired while still being held",
+        &["bucket"]
+    );
+    pub static ref LOCK_CONTENDED_COUNTER: IntCounterVec = counter_vec(
+        "lock_contended_total",
+        "Lock requests that found another request of this instance on the same bucket",
+        &["bucket"]
+    );
+    pub static ref LOCK_INFLIGHT_GAUGE: IntGaugeVec = gauge_vec(
+        "lock_inflight",
+        "Requests of this instance currently waiting for or holding a lock",
+        &["bucket"]
+    );
+    pub static ref LOCK_FALLBACK_COUNTER: IntCounter = IntCounter::new(
+        "lock_fallback_total",
+        "Transactions written without the lock because redis was unreachable"
     )
     .unwrap();
+    pub static ref RATE_LIMITED_COUNTER: IntCounter = IntCounter::new(
+        "rate_limited_total",
+        "Requests rejected for exceeding the rate limit of their client"
+    )
+    .unwrap();
+    pub static ref SHED_COUNTER: IntCounterVec = counter_vec(
+        "requests_shed_total",
+        "Requests failed fast because the instance or a dependency was saturated",
+        &["reason"]
+    );
+    pub static ref CONCURRENCY_LIMIT_GAUGE: IntGauge = IntGauge::new(
+        "concurrency_limit",
+        "Current adaptive limit of concurrent requests"
+    )
+    .unwrap();
+    pub static ref BREAKER_STATE_GAUGE: IntGaugeVec = gauge_vec(
+        "circuit_breaker_state",
+        "State of the circuit breakers (0 closed, 1 half open, 2 open)",
+        &["breaker"]
+    );
+    pub static ref BREAKER_REJECTIONS_COUNTER: IntCounterVec = counter_vec(
+        "circuit_breaker_rejections_total",
+        "Calls failed fast because the circuit breaker of the dependency was open",
+        &["breaker"]
+    );
 }
 
-pub fn register(registry: &prometheus::Registry) {
-    registry.register(Box::new(OPS_HISTOGRAM.clone())).unwrap();
+/// Creates the histograms with the configured buckets and registers every metric into
+/// `registry`. Histograms can't be used before, and it fails when called a second time.
+pub fn register(registry: &prometheus::Registry, conf: &AppConfig) -> prometheus::Result<()> {
+    let buckets = &conf.metrics.buckets;
+
+    let collectors: Vec<Box<dyn Collector>> = vec![
+        Box::new(OPS_HISTOGRAM.create(buckets)?),
+        Box::new(SQL_HISTOGRAM.create(buckets)?),
+        Box::new(SLOW_QUERIES_COUNTER.clone()),
+        Box::new(HTTP_REQUESTS_COUNTER.clone()),
+        Box::new(HTTP_DURATION_HISTOGRAM.create(buckets)?),
+        Box::new(PANICS_COUNTER.clone()),
+        Box::new(TRANSACTIONS_COUNTER.clone()),
+        Box::new(TRANSACTION_REJECTIONS_COUNTER.clone()),
+        Box::new(DB_RETRIES_COUNTER.clone()),
+        Box::new(DB_RETRIES_EXHAUSTED_COUNTER.clone()),
+        Box::new(DB_POOL_CONNECTIONS_GAUGE.clone()),
+        Box::new(DB_POOL_WAITERS_GAUGE.clone()),
+        Box::new(LOCK_WAIT_HISTOGRAM.create(buckets)?),
+        Box::new(LOCK_HOLD_HISTOGRAM.create(buckets)?),
+        Box::new(LOCK_FAILURES_COUNTER.clone()),
+        Box::new(LOCK_EXPIRED_COUNTER.clone()),
+        Box::new(LOCK_CONTENDED_COUNTER.clone()),
+        Box::new(LOCK_INFLIGHT_GAUGE.clone()),
+        Box::new(LOCK_FALLBACK_COUNTER.clone()),
+        Box::new(RATE_LIMITED_COUNTER.clone()),
+        Box::new(SHED_COUNTER.clone()),
+        Box::new(CONCURRENCY_LIMIT_GAUGE.clone()),
+        Box::new(BREAKER_STATE_GAUGE.clone()),
+        Box::new(BREAKER_REJECTIONS_COUNTER.clone()),
+    ];
+
+    for collector in collectors {
+        registry.register(collector)?;
+    }
+
+    #[cfg(feature = "diagnostics")]
+    crate::tools::diagnostics::register(registry)?;
+
+    Ok(())
+}
+
+/// counts and times requests by matched route, so that path parameters don't blow up cardinality
+pub async fn track(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let status = response.status().as_u16().to_string();
+
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    HTTP_REQUESTS_COUNTER.with_label_values(&labels).inc();
+    HTTP_DURATION_HISTOGRAM
+        .with_label_values(&labels)
+        .observe(start.elapsed().as_secs_f64());
+
+    response
 }
 
 pub async fn get(State(registry): State<Arc<prometheus::Registry>>) -> impl IntoResponse {
@@ -29,13 +255,13 @@ pub async fn get(State(registry): State<Arc<prometheus::Registry>>) -> impl Into
 }
 
 pub struct DeferredObserve<'a> {
-    histogram: &'a HistogramVec,
+    histogram: &'a HistogramFamily,
     label: &'a [&'a str],
     start_time: Instant,
 }
 
 impl<'a> DeferredObserve<'a> {
-    pub fn new(histogram: &'a HistogramVec, label: &'a [&'a str]) -> Self {
+    pub fn new(histogram: &'a HistogramFamily, label: &'a [&'a str]) -> Self {
         Self {
             histogram,
             label,
@@ -52,3 +278,21 @@ impl<'a> Drop for DeferredObserve<'a> {
             .observe(elapsed_time);
     }
 }
+
+/// keeps a gauge incremented for as long as the guard is alive
+pub struct GaugeGuard {
+    gauge: IntGauge,
+}
+
+impl GaugeGuard {
+    pub fn new(gauge: IntGauge) -> Self {
+        gauge.inc();
+        Self { gauge }
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
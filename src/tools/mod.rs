@@ -1,6 +1,21 @@
+pub mod auth;
 pub mod axum;
+pub mod breaker;
 pub mod db;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
 pub mod error;
+pub mod health;
+pub mod i18n;
 pub mod json;
 pub mod locker;
+pub mod logging;
 pub mod metrics;
+pub mod overload;
+pub mod problem;
+pub mod rate_limit;
+pub mod redis_connection;
+pub mod request_id;
+pub mod retry;
+pub mod shutdown;
+pub mod telemetry;
@@ -0,0 +1,286 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::config::app_config::AppConfig;
+use crate::tools::error::{CustomError, DomainError};
+use crate::tools::metrics::{CONCURRENCY_LIMIT_GAUGE, SHED_COUNTER};
+use crate::tools::problem::ErrorCode;
+
+/// AIMD limit on concurrent requests: it grows by one while requests complete within
+/// `target_latency` and shrinks by 10% when they don't (or when a dependency sheds them), so the
+/// instance queues less work than it can hold in memory when sqld or redis slow down.
+pub struct ConcurrencyLimiter {
+    enabled: bool,
+    min: usize,
+    max: usize,
+    target_latency: Duration,
+    limit: AtomicUsize,
+    inflight: Arc<AtomicUsize>,
+}
+
+struct InflightGuard {
+    inflight: Arc<AtomicUsize>,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.inflight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(conf: &AppConfig) -> Self {
+        let conf = &conf.load_shedding.concurrency;
+        CONCURRENCY_LIMIT_GAUGE.set(conf.initial as i64);
+
+        Self {
+            enabled: conf.enabled,
+            min: conf.min,
+            max: conf.max,
+            target_latency: conf.target_latency,
+            limit: AtomicUsize::new(conf.initial),
+            inflight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn try_acquire(&self) -> Option<InflightGuard> {
+        let limit = self.limit.load(Ordering::Relaxed);
+
+        self.inflight
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |inflight| {
+                (inflight < limit).then_some(inflight + 1)
+            })
+            .ok()
+            .map(|_| InflightGuard {
+                inflight: self.inflight.clone(),
+            })
+    }
+
+    fn record(&self, latency: Duration, overloaded: bool) {
+        let inflight = self.inflight.load(Ordering::Relaxed);
+
+        let updated = self
+            .limit
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |limit| {
+                if overloaded || latency > self.target_latency {
+                    Some((limit * 9 / 10).max(self.min))
+                } else if inflight * 2 >= limit {
+                    // only grows while the current limit is actually being used
+                    Some((limit + 1).min(self.max))
+                } else {
+                    None
+                }
+            });
+
+        if let Ok(previous) = updated {
+            CONCURRENCY_LIMIT_GAUGE.set(self.limit.load(Ordering::Relaxed) as i64);
+            tracing::trace!("concurrency limit updated from {}", previous);
+        }
+    }
+}
+
+/// rejects requests beyond the current concurrency limit with 503 before any work is done
+pub async fn shed(
+    State(limiter): State<Arc<ConcurrencyLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !limiter.enabled {
+        return next.run(request).await;
+    }
+
+    let Some(_guard) = limiter.try_acquire() else {
+        SHED_COUNTER.with_label_values(&["concurrency"]).inc();
+        let message = format!(
+            "concurrency limit of {} reached",
+            limiter.limit.load(Ordering::Relaxed)
+        );
+
+        return CustomError::Unavailable(message).into_response();
+    };
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let overloaded = response.status() == StatusCode::SERVICE_UNAVAILABLE;
+    limiter.record(start.elapsed(), overloaded);
+
+    response
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    at: Instant,
+    budget: Duration,
+}
+
+tokio::task_local! {
+    static DEADLINE: Deadline;
+}
+
+impl Deadline {
+    fn exceeded(&self) -> DeadlineExceeded {
+        SHED_COUNTER.with_label_values(&["deadline"]).inc();
+        DeadlineExceeded(self.budget)
+    }
+}
+
+/// a wait cut short by the deadline of the request, reported as 503
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("request did not complete within {0:?}")]
+pub struct DeadlineExceeded(pub Duration);
+
+impl From<DeadlineExceeded> for CustomError {
+    fn from(err: DeadlineExceeded) -> Self {
+        DomainError::new(ErrorCode::Timeout, err.to_string())
+            .with_param("deadline_ms", err.0.as_millis())
+            .into()
+    }
+}
+
+/// why [`bounded`] gave up waiting
+#[derive(Debug)]
+pub enum GaveUp {
+    /// the limit of the wait itself
+    Limit,
+    Deadline(DeadlineExceeded),
+}
+
+/// Waits for `future` at most `limit`, and no longer than the deadline of the request being
+/// served allows, if any (see [`deadline`]).
+pub async fn bounded<F: Future>(limit: Duration, future: F) -> Result<F::Output, GaveUp> {
+    let deadline = DEADLINE
+        .try_with(|deadline| *deadline)
+        .ok()
+        .map(|deadline| {
+            (
+                deadline.at.saturating_duration_since(Instant::now()),
+                deadline,
+            )
+        })
+        .filter(|(remaining, _)| *remaining < limit);
+
+    let timeout = deadline.map_or(limit, |(remaining, _)| remaining);
+    tokio::time::timeout(timeout, future)
+        .await
+        .map_err(|_| match deadline {
+            Some((_, deadline)) => GaveUp::Deadline(deadline.exceeded()),
+            None => GaveUp::Limit,
+        })
+}
+
+/// waits for `future` for as long as the deadline of the request being served allows, if any
+pub async fn within_deadline<F: Future>(future: F) -> Result<F::Output, DeadlineExceeded> {
+    match DEADLINE.try_with(|deadline| *deadline) {
+        Ok(deadline) => tokio::time::timeout_at(deadline.at.into(), future)
+            .await
+            .map_err(|_| deadline.exceeded()),
+        Err(_) => Ok(future.await),
+    }
+}
+
+/// Gives the request `budget` to get hold of what it waits for (a database connection, a lock,
+/// a place in a batch), failing it with 503 past that. The handler itself is never cancelled,
+/// so a write that got going always runs to completion.
+pub async fn deadline(State(budget): State<Duration>, request: Request, next: Next) -> Response {
+    let deadline = Deadline {
+        at: Instant::now() + budget,
+        budget,
+    };
+
+    DEADLINE.scope(deadline, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(initial: usize) -> ConcurrencyLimiter {
+        ConcurrencyLimiter {
+            enabled: true,
+            min: 4,
+            max: 12,
+            target_latency: Duration::from_millis(100),
+            limit: AtomicUsize::new(initial),
+            inflight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn limit(limiter: &ConcurrencyLimiter) -> usize {
+        limiter.limit.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn rejects_beyond_the_limit() {
+        let limiter = limiter(4);
+        let guards = (0..4)
+            .filter_map(|_| limiter.try_acquire())
+            .collect::<Vec<_>>();
+
+        assert_eq!(guards.len(), 4);
+        assert!(limiter.try_acquire().is_none());
+
+        drop(guards);
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[test]
+    fn shrinks_on_slow_or_shed_requests() {
+        let limiter = limiter(10);
+
+        limiter.record(Duration::from_millis(150), false);
+        assert_eq!(limit(&limiter), 9);
+
+        limiter.record(Duration::ZERO, true);
+        assert_eq!(limit(&limiter), 8);
+
+        for _ in 0..10 {
+            limiter.record(Duration::ZERO, true);
+        }
+        assert_eq!(limit(&limiter), 4);
+    }
+
+    #[test]
+    fn grows_only_while_used() {
+        let limiter = limiter(10);
+
+        limiter.record(Duration::ZERO, false);
+        assert_eq!(limit(&limiter), 10);
+
+        let _guards = (0..6)
+            .filter_map(|_| limiter.try_acquire())
+            .collect::<Vec<_>>();
+        for _ in 0..5 {
+            limiter.record(Duration::ZERO, false);
+        }
+        assert_eq!(limit(&limiter), 12);
+    }
+
+    #[tokio::test]
+    async fn bounded_by_the_closest_limit() {
+        let never = std::future::pending::<()>;
+
+        let limit = bounded(Duration::from_millis(1), never()).await;
+        assert!(matches!(limit, Err(GaveUp::Limit)));
+
+        let deadline = Deadline {
+            at: Instant::now() + Duration::from_millis(1),
+            budget: Duration::from_millis(1),
+        };
+        let cut = DEADLINE
+            .scope(deadline, bounded(Duration::from_secs(60), never()))
+            .await;
+        assert!(matches!(cut, Err(GaveUp::Deadline(_))));
+    }
+
+    #[tokio::test]
+    async fn waits_without_a_deadline() {
+        assert_eq!(within_deadline(async { 1 }).await.unwrap(), 1);
+    }
+}
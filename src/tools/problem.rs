@@ -0,0 +1,250 @@
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::header::{CONTENT_LANGUAGE, CONTENT_TYPE};
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::{Serialize, Serializer};
+use utoipa::ToSchema;
+
+use crate::tools::i18n::{self, Locale};
+use crate::tools::request_id::RequestId;
+
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// Catalog of the errors exposed by the API. The codes are part of the contract: clients match
+/// on them, so existing ones must never be renamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Unexpected,
+    InvalidRequest,
+    InvalidBody,
+    UnsupportedMediaType,
+    ValidationFailed,
+    ClientNotFound,
+    InsufficientFunds,
+    Unauthenticated,
+    Forbidden,
+    RateLimited,
+    Unavailable,
+    Timeout,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Unexpected => "unexpected",
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::InvalidBody => "invalid_body",
+            ErrorCode::UnsupportedMediaType => "unsupported_media_type",
+            ErrorCode::ValidationFailed => "validation_failed",
+            ErrorCode::ClientNotFound => "client_not_found",
+            ErrorCode::InsufficientFunds => "insufficient_funds",
+            ErrorCode::Unauthenticated => "unauthenticated",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::Unavailable => "unavailable",
+            ErrorCode::Timeout => "timeout",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::Unexpected => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidBody => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::ValidationFailed => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::ClientNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::InsufficientFunds => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::Unauthenticated => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub fn type_uri(&self) -> String {
+        format!("/errors/{}", self.as_str())
+    }
+}
+
+/// Why a request was rejected before reaching a handler, or which constraint it broke. Picks
+/// the localized detail of the errors that have no template of their own, so that callers
+/// never get the raw parser or validator messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// a path parameter, named by the field, doesn't parse
+    InvalidParam,
+    InvalidPath,
+    UnsupportedContentType,
+    UnreadableBody,
+    MalformedJson,
+    /// well-formed json with a field of the wrong type or value
+    InvalidField,
+    /// well-formed json with the wrong shape, e.g. a missing field
+    InvalidShape,
+    OutOfRange,
+    InvalidLength,
+    NotAllowed,
+    InvalidValue,
+    InvalidData,
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// RFC 7807 problem details, with the catalog code and optional extension members
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    #[schema(example = "/errors/client_not_found")]
+    pub type_uri: String,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+
+    /// echoes `X-Request-Id`, to correlate the error with the logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+
+    #[schema(value_type = String, example = "client_not_found")]
+    pub code: ErrorCode,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+
+    /// validation errors, only present for `validation_failed`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub errors: Option<serde_json::Value>,
+
+    /// values for the placeholders of the localized detail
+    #[serde(skip)]
+    pub params: Vec<(&'static str, String)>,
+
+    #[serde(skip)]
+    pub reason: Option<Reason>,
+
+    #[serde(skip)]
+    pub locale: Locale,
+}
+
+impl Problem {
+    pub fn new(code: ErrorCode, detail: String) -> Self {
+        Self {
+            type_uri: code.type_uri(),
+            title: i18n::title(code, Locale::default()),
+            status: code.status().as_u16(),
+            detail,
+            instance: None,
+            request_id: None,
+            code,
+            field: None,
+            errors: None,
+            params: Vec::new(),
+            reason: None,
+            locale: Locale::default(),
+        }
+        .localize(Locale::default())
+    }
+
+    pub fn with_params(mut self, params: Vec<(&'static str, String)>) -> Self {
+        self.params = params;
+        let locale = self.locale;
+        self.localize(locale)
+    }
+
+    pub fn with_field(mut self, field: Option<String>) -> Self {
+        self.field = field;
+        let locale = self.locale;
+        self.localize(locale)
+    }
+
+    pub fn with_reason(mut self, reason: Reason) -> Self {
+        self.reason = Some(reason);
+        let locale = self.locale;
+        self.localize(locale)
+    }
+
+    pub fn with_errors(mut self, errors: serde_json::Value) -> Self {
+        self.errors = Some(errors);
+        self
+    }
+
+    /// translates title and detail using the message catalog
+    pub fn localize(mut self, locale: Locale) -> Self {
+        self.locale = locale;
+        self.title = i18n::title(self.code, locale);
+        let mut params = self.params.clone();
+        if let Some(field) = &self.field {
+            params.push(("field", field.clone()));
+        }
+        if let Some(detail) = i18n::detail(self.code, self.reason, locale, &params) {
+            self.detail = detail;
+        }
+
+        self
+    }
+
+    fn body(&self) -> Body {
+        Body::from(serde_json::to_vec(self).unwrap_or_default())
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut response = Response::new(self.body());
+
+        *response.status_mut() = status;
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_CONTENT_TYPE));
+        response.headers_mut().insert(
+            CONTENT_LANGUAGE,
+            HeaderValue::from_static(self.locale.tag()),
+        );
+        // kept around so that middlewares can enrich the body (see `enrich`)
+        response.extensions_mut().insert(self);
+
+        response
+    }
+}
+
+/// Fills in what is not known where errors are rendered: `instance` with the request path,
+/// `request_id`, and the language of the messages from `Accept-Language`.
+pub async fn enrich(request: Request, next: Next) -> Response {
+    let path = request.uri().path().to_string();
+    let locale = Locale::from_headers(request.headers());
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone());
+    let response = next.run(request).await;
+
+    let (mut parts, body) = response.into_parts();
+    let Some(problem) = parts.extensions.remove::<Problem>() else {
+        return Response::from_parts(parts, body);
+    };
+
+    let mut problem = problem.localize(locale);
+    problem.instance = Some(path);
+    problem.request_id = request_id;
+
+    parts.headers.insert(
+        CONTENT_LANGUAGE,
+        HeaderValue::from_static(problem.locale.tag()),
+    );
+    let body = problem.body();
+    parts.extensions.insert(problem);
+
+    Response::from_parts(parts, body)
+}
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{Request, State};
+use axum::http::header::RETRY_AFTER;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use redis::{RedisResult, Script};
+
+use crate::config::app_config::AppConfig;
+use crate::tools::auth::Principal;
+use crate::tools::axum::Path;
+use crate::tools::error::{CustomError, DomainError};
+use crate::tools::metrics::RATE_LIMITED_COUNTER;
+use crate::tools::problem::ErrorCode;
+use crate::tools::redis_connection::SharedConnection;
+
+// at most this many buckets are kept locally, see `LocalBuckets`
+const MAX_LOCAL_KEYS: usize = 10_000;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+// refills the bucket from the time elapsed on the redis clock, so both instances agree on it,
+// and returns how many milliseconds to wait for a token (0 when one was taken)
+const TOKEN_BUCKET_SCRIPT: &str = r"
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated_at')
+local tokens = tonumber(bucket[1]) or burst
+local updated_at = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated_at) * rate / 1000)
+
+local wait = 0
+if tokens >= 1 then
+  tokens = tokens - 1
+else
+  wait = math.ceil((1 - tokens) * 1000 / rate)
+end
+
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated_at', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst * 1000 / rate) + 1000)
+
+return wait
+";
+
+/// client a bucket is kept for, along with the caller when limits are per principal
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BucketKey {
+    client_id: u32,
+    principal: Option<String>,
+}
+
+impl Display for BucketKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.principal {
+            Some(principal) => write!(f, "{}:{}", self.client_id, principal),
+            None => write!(f, "{}", self.client_id),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Buckets in two generations: once the current one is full it becomes the previous one, and
+/// the previous one is dropped. Buckets in use move back to the current generation, so only
+/// those left idle for a whole generation are lost, and those are usually full again anyway.
+#[derive(Default)]
+struct LocalBuckets {
+    current: HashMap<BucketKey, Bucket>,
+    previous: HashMap<BucketKey, Bucket>,
+}
+
+impl LocalBuckets {
+    fn get(&mut self, key: &BucketKey, burst: f64, now: Instant) -> &mut Bucket {
+        if !self.current.contains_key(key) && self.current.len() >= MAX_LOCAL_KEYS / 2 {
+            self.previous = std::mem::take(&mut self.current);
+        }
+
+        let previous = &mut self.previous;
+        self.current.entry(key.clone()).or_insert_with(|| {
+            previous.remove(key).unwrap_or(Bucket {
+                tokens: burst,
+                updated_at: now,
+            })
+        })
+    }
+}
+
+/// Token bucket limiter keyed by client id (and optionally by caller). With the `redis` backend
+/// the buckets are shared by every instance; if redis can't be reached the local buckets are used
+/// instead, so limits still hold per instance.
+pub struct RateLimiter {
+    enabled: bool,
+    per_principal: bool,
+    rate: AtomicU32,
+    burst: AtomicU32,
+    local: Mutex<LocalBuckets>,
+    redis: Option<(SharedConnection, Script)>,
+}
+
+impl RateLimiter {
+    pub fn new(conf: &AppConfig) -> RedisResult<Self> {
+        let redis = match conf.rate_limit.backend.as_str() {
+            "redis" => {
+                let addr = format!("redis://{}:{}/", conf.redis.host, conf.redis.port);
+                let connection = SharedConnection::new(redis::Client::open(addr)?, CONNECT_TIMEOUT);
+                Some((connection, Script::new(TOKEN_BUCKET_SCRIPT)))
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            enabled: conf.rate_limit.enabled,
+            per_principal: conf.rate_limit.per_principal,
+            rate: AtomicU32::new(conf.rate_limit.rate),
+            burst: AtomicU32::new(conf.rate_limit.burst),
+            local: Mutex::new(LocalBuckets::default()),
+            redis,
+        })
+    }
+
+    /// only affects requests from now on, existing buckets keep their tokens
+    pub fn set_limits(&self, rate: u32, burst: u32) {
+        self.rate.store(rate, Ordering::Relaxed);
+        self.burst.store(burst, Ordering::Relaxed);
+    }
+
+    fn limits(&self) -> (f64, f64) {
+        (
+            self.rate.load(Ordering::Relaxed) as f64,
+            self.burst.load(Ordering::Relaxed) as f64,
+        )
+    }
+
+    fn key(&self, client_id: u32, principal: Option<&Principal>) -> BucketKey {
+        BucketKey {
+            client_id,
+            principal: principal
+                .filter(|_| self.per_principal)
+                .map(|principal| principal.name.clone()),
+        }
+    }
+
+    /// takes a token for `key`, or returns how long until one is available
+    pub async fn acquire(&self, key: &BucketKey) -> Result<(), Duration> {
+        if let Some((connection, script)) = &self.redis {
+            match self.acquire_shared(connection, script, key).await {
+                Ok(wait) => return wait,
+                Err(err) => {
+                    tracing::warn!(
+                        "shared rate limit unavailable, using local buckets: {}",
+                        err
+                    );
+                }
+            }
+        }
+
+        self.acquire_local(key)
+    }
+
+    /// while redis is down, the shared connection fails fast between reconnection attempts
+    async fn acquire_shared(
+        &self,
+        connection: &SharedConnection,
+        script: &Script,
+        key: &BucketKey,
+    ) -> RedisResult<Result<(), Duration>> {
+        let mut conn = connection.get().await?;
+
+        let (rate, burst) = self.limits();
+        let wait_ms: u64 = script
+            .key(format!("rate_limit:{}", key))
+            .arg(rate)
+            .arg(burst)
+            .invoke_async(&mut conn)
+            .await
+            .inspect_err(|_| connection.reset())?;
+
+        Ok(match wait_ms {
+            0 => Ok(()),
+            wait_ms => Err(Duration::from_millis(wait_ms)),
+        })
+    }
+
+    fn acquire_local(&self, key: &BucketKey) -> Result<(), Duration> {
+        let (rate, burst) = self.limits();
+        let now = Instant::now();
+        let mut buckets = self.local.lock().unwrap();
+        let bucket = buckets.get(key, burst, now);
+
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// Rejects requests over the limit of their `client_id` with 429 and `Retry-After`. Runs after
+/// [`crate::tools::auth::authorize`], which provides the caller.
+pub async fn limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    Path(params): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !limiter.enabled {
+        return next.run(request).await;
+    }
+
+    // ids that don't parse are left for the handler to reject
+    let client_id = params
+        .get("client_id")
+        .and_then(|id| id.parse::<u32>().ok());
+    let Some(client_id) = client_id else {
+        return next.run(request).await;
+    };
+
+    let key = limiter.key(client_id, request.extensions().get::<Principal>());
+
+    let Err(wait) = limiter.acquire(&key).await else {
+        return next.run(request).await;
+    };
+
+    RATE_LIMITED_COUNTER.inc();
+
+    // Retry-After only takes whole seconds
+    let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+    let message = format!("rate limit exceeded for {}, retry in {:?}", key, wait);
+    let error = DomainError::new(ErrorCode::RateLimited, message)
+        .with_param("client_id", client_id)
+        .with_param("retry_after", retry_after);
+
+    let mut response = CustomError::from(error).into_response();
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::auth::Role;
+
+    fn limiter(rate: u32, burst: u32) -> RateLimiter {
+        RateLimiter {
+            enabled: true,
+            per_principal: false,
+            rate: AtomicU32::new(rate),
+            burst: AtomicU32::new(burst),
+            local: Mutex::new(LocalBuckets::default()),
+            redis: None,
+        }
+    }
+
+    fn key(client_id: u32) -> BucketKey {
+        BucketKey {
+            client_id,
+            principal: None,
+        }
+    }
+
+    #[test]
+    fn allows_bursts_then_waits() {
+        let limiter = limiter(10, 3);
+
+        for _ in 0..3 {
+            assert!(limiter.acquire_local(&key(1)).is_ok());
+        }
+
+        let wait = limiter.acquire_local(&key(1)).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = limiter(100, 1);
+
+        assert!(limiter.acquire_local(&key(1)).is_ok());
+        assert!(limiter.acquire_local(&key(1)).is_err());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter.acquire_local(&key(1)).is_ok());
+    }
+
+    #[test]
+    fn buckets_are_per_key() {
+        let limiter = limiter(1, 1);
+
+        assert!(limiter.acquire_local(&key(1)).is_ok());
+        assert!(limiter.acquire_local(&key(2)).is_ok());
+        assert!(limiter.acquire_local(&key(1)).is_err());
+    }
+
+    #[test]
+    fn principal_only_counts_when_enabled() {
+        let mut limiter = limiter(1, 1);
+        let principal = Principal {
+            name: "backoffice".into(),
+            role: Role::Client,
+            clients: vec![1],
+        };
+
+        assert_eq!(limiter.key(1, Some(&principal)), key(1));
+
+        limiter.per_principal = true;
+        assert_eq!(limiter.key(1, Some(&principal)).to_string(), "1:backoffice");
+    }
+
+    #[test]
+    fn local_buckets_are_bounded() {
+        let limiter = limiter(1, 1);
+
+        for client_id in 0..(MAX_LOCAL_KEYS as u32 * 3) {
+            let _ = limiter.acquire_local(&key(client_id));
+        }
+
+        let buckets = limiter.local.lock().unwrap();
+        assert!(buckets.current.len() + buckets.previous.len() <= MAX_LOCAL_KEYS);
+    }
+
+    #[test]
+    fn buckets_in_use_survive_rotation() {
+        let limiter = limiter(1, 1);
+        assert!(limiter.acquire_local(&key(0)).is_ok());
+
+        for client_id in 1..(MAX_LOCAL_KEYS as u32) {
+            let _ = limiter.acquire_local(&key(client_id));
+            if client_id % 1000 == 0 {
+                assert!(limiter.acquire_local(&key(0)).is_err());
+            }
+        }
+    }
+}
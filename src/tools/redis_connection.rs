@@ -0,0 +1,73 @@
+use std::io::{Error, ErrorKind};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use redis::aio::MultiplexedConnection;
+use redis::{RedisError, RedisResult};
+
+// after a failed connection, requests fail fast for this long instead of each reconnecting
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+
+enum State {
+    Disconnected,
+    Connected(MultiplexedConnection),
+    Failed(Instant),
+}
+
+/// Multiplexed connection shared by every request, opened on first use. Connecting happens
+/// outside of the lock, so requests never queue behind a connection attempt, and once one fails
+/// the next is only made after `RECONNECT_BACKOFF`.
+pub struct SharedConnection {
+    client: redis::Client,
+    connect_timeout: Duration,
+    state: Mutex<State>,
+}
+
+impl SharedConnection {
+    pub fn new(client: redis::Client, connect_timeout: Duration) -> Self {
+        Self {
+            client,
+            connect_timeout,
+            state: Mutex::new(State::Disconnected),
+        }
+    }
+
+    pub async fn get(&self) -> RedisResult<MultiplexedConnection> {
+        match &*self.state.lock().unwrap() {
+            State::Connected(conn) => return Ok(conn.clone()),
+            State::Failed(at) if at.elapsed() < RECONNECT_BACKOFF => {
+                return Err(RedisError::from(Error::new(
+                    ErrorKind::NotConnected,
+                    "waiting to reconnect to redis",
+                )));
+            }
+            _ => {}
+        }
+
+        let connect = self.client.get_multiplexed_tokio_connection();
+        let result = match tokio::time::timeout(self.connect_timeout, connect).await {
+            Ok(result) => result,
+            Err(_) => Err(RedisError::from(Error::new(
+                ErrorKind::TimedOut,
+                "timed out connecting to redis",
+            ))),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        match &result {
+            // another request may have connected meanwhile, either connection will do
+            Ok(conn) => *state = State::Connected(conn.clone()),
+            Err(_) => *state = State::Failed(Instant::now()),
+        }
+
+        result
+    }
+
+    /// drops the connection after an error, the next request reconnects
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let State::Connected(_) = *state {
+            *state = State::Disconnected;
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::borrow::Cow;
+
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::tools::telemetry;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const MAX_LEN: usize = 128;
+
+/// id of the request being served, available as a request extension
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// id of the request the current task is serving, if any
+pub fn current() -> Option<String> {
+    CURRENT.try_with(|id| id.0.clone()).ok()
+}
+
+/// prefixes `sql` with a comment carrying the request id, so statements can be traced in sqld
+pub fn tag_sql(sql: &str) -> Cow<'_, str> {
+    tag_sql_with(current().as_deref(), sql)
+}
+
+/// same as [`tag_sql`], for work done outside of the task serving the request
+pub fn tag_sql_with<'a>(id: Option<&str>, sql: &'a str) -> Cow<'a, str> {
+    match id {
+        Some(id) => Cow::Owned(format!("/* request_id={} */ {}", id, sql)),
+        None => Cow::Borrowed(sql),
+    }
+}
+
+// ids end up in headers and sql comments, so only a conservative charset is accepted
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// Reuses the caller's `X-Request-Id` or generates one, opens the request span with it and
+/// echoes it back in the response
+pub async fn propagate(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let span = tracing::info_span!(
+        "request",
+        otel.kind = "server",
+        request_id = %id,
+        method = %request.method(),
+        path = %request.uri().path(),
+        status = tracing::field::Empty,
+    );
+    telemetry::set_remote_parent(&span, request.headers());
+
+    request.extensions_mut().insert(RequestId(id.clone()));
+    let mut response = CURRENT
+        .scope(RequestId(id.clone()), next.run(request))
+        .instrument(span.clone())
+        .await;
+    span.record("status", response.status().as_u16());
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    response
+}
@@ -0,0 +1,203 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use crate::config::app_config;
+use crate::tools::db;
+use crate::tools::error::CustomError;
+use crate::tools::metrics::{DB_RETRIES_COUNTER, DB_RETRIES_EXHAUSTED_COUNTER};
+
+/// Tries database work again when it fails with a transient error, waiting an exponential
+/// backoff between attempts. Only wrap whole units that can be replayed: a single read, or a
+/// whole transaction (a commit that may have gone through is never retried, see
+/// [`db::DbError::transient`]).
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(conf: &app_config::Retry) -> Self {
+        Self {
+            max_attempts: conf.max_attempts.max(1),
+            backoff: conf.backoff,
+            max_backoff: conf.max_backoff,
+        }
+    }
+
+    pub async fn run<F, Fut, T>(&self, operation: &'static str, f: F) -> Result<T, CustomError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, CustomError>>,
+    {
+        self.run_until(operation, None, f).await
+    }
+
+    /// only retries while another attempt, as long as the last one, would end before `until`,
+    /// e.g. the expiry of the lock the work runs under
+    pub async fn run_before<F, Fut, T>(
+        &self,
+        operation: &'static str,
+        until: Instant,
+        f: F,
+    ) -> Result<T, CustomError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, CustomError>>,
+    {
+        self.run_until(operation, Some(until), f).await
+    }
+
+    async fn run_until<F, Fut, T>(
+        &self,
+        operation: &'static str,
+        until: Option<Instant>,
+        mut f: F,
+    ) -> Result<T, CustomError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, CustomError>>,
+    {
+        let mut attempt = 1;
+        let mut backoff = self.backoff;
+
+        loop {
+            let start = Instant::now();
+            let result = f().await;
+            let Err(CustomError::Unexpected(err)) = &result else {
+                return result;
+            };
+
+            if db::transient_cause(err).is_none() {
+                return result;
+            }
+
+            let out_of_time =
+                until.is_some_and(|until| Instant::now() + backoff + start.elapsed() >= until);
+            if attempt >= self.max_attempts || out_of_time {
+                DB_RETRIES_EXHAUSTED_COUNTER
+                    .with_label_values(&[operation])
+                    .inc();
+                return result;
+            }
+
+            tracing::warn!(
+                "Retrying {} after a transient error (attempt {}/{}): {:#}",
+                operation,
+                attempt,
+                self.max_attempts,
+                err
+            );
+            DB_RETRIES_COUNTER.with_label_values(&[operation]).inc();
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.max_backoff);
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use anyhow::Context;
+
+    use super::*;
+    use crate::tools::db::DbError;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy::new(&app_config::Retry {
+            max_attempts,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        })
+    }
+
+    fn busy() -> CustomError {
+        let err = DbError::Libsql(libsql::Error::SqliteFailure(5, "database is locked".into()));
+        Err::<(), _>(err)
+            .context("failed to query for rows")
+            .unwrap_err()
+            .into()
+    }
+
+    fn commit_unreachable() -> CustomError {
+        let err = DbError::Commit(libsql::Error::ConnectionFailed("reset".into()));
+        anyhow::Error::new(err).into()
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors() {
+        let calls = Cell::new(0);
+        let result = policy(3)
+            .run("test", || async {
+                calls.set(calls.get() + 1);
+                if calls.get() < 3 {
+                    Err(busy())
+                } else {
+                    Ok(calls.get())
+                }
+            })
+            .await;
+
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let calls = Cell::new(0);
+        let result = policy(2)
+            .run("test", || async {
+                calls.set(calls.get() + 1);
+                Err::<(), _>(busy())
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_other_errors() {
+        let calls = Cell::new(0);
+        let result = policy(3)
+            .run("test", || async {
+                calls.set(calls.get() + 1);
+                Err::<(), _>(CustomError::Unexpected(anyhow::anyhow!("parse error")))
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn doesnt_replay_unreachable_commits() {
+        let calls = Cell::new(0);
+        let result = policy(3)
+            .run("test", || async {
+                calls.set(calls.get() + 1);
+                Err::<(), _>(commit_unreachable())
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn stops_before_the_deadline() {
+        let calls = Cell::new(0);
+        let result = policy(3)
+            .run_before("test", Instant::now(), || async {
+                calls.set(calls.get() + 1);
+                Err::<(), _>(busy())
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+}
@@ -0,0 +1,41 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+
+#[derive(Default)]
+pub struct Shutdown {
+    draining: AtomicBool,
+}
+
+impl Shutdown {
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once SIGTERM or SIGINT is received and the drain period is over. While draining
+    /// requests are still served, but `/health` reports unavailable so haproxy stops routing here.
+    pub async fn wait(self: Arc<Self>, drain: Duration) {
+        let terminate = async {
+            match signal(SignalKind::terminate()) {
+                Ok(mut terminate) => terminate.recv().await,
+                Err(err) => {
+                    tracing::warn!("cannot listen for SIGTERM: {}", err);
+                    std::future::pending().await
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = terminate => tracing::info!("SIGTERM received"),
+            _ = tokio::signal::ctrl_c() => tracing::info!("SIGINT received"),
+        }
+
+        self.draining.store(true, Ordering::Relaxed);
+        tracing::info!("Draining for {:?} before shutting down", drain);
+
+        tokio::time::sleep(drain).await;
+        tracing::info!("Waiting for in-flight requests to complete");
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::Context;
+use axum::http::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self, Sampler, Tracer};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::app_config::AppConfig;
+
+/// Sets up the OTLP (gRPC) exporter spans are sent to in batches, and W3C trace context
+/// propagation for incoming requests
+pub fn tracer(conf: &AppConfig) -> anyhow::Result<Tracer> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(&conf.telemetry.endpoint)
+        .with_timeout(conf.telemetry.timeout);
+
+    // callers that already sampled (or dropped) a trace keep the decision
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+        conf.telemetry.sample_ratio,
+    )));
+    let resource = Resource::new([KeyValue::new(
+        "service.name",
+        conf.telemetry.service_name.clone(),
+    )]);
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(
+            trace::config()
+                .with_sampler(sampler)
+                .with_resource(resource),
+        )
+        .install_batch(runtime::Tokio)
+        .context("failed to install the OTLP exporter")
+}
+
+/// flushes the spans not exported yet, used on shutdown
+pub async fn shutdown() {
+    // blocks until the exporter is done
+    let _ = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// continues the trace of the caller from its `traceparent` header, if any
+pub fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+
+    span.set_parent(parent);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use config::{Config, File};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use opentelemetry::trace::{TraceContextExt, Tracer as _};
+    use tokio::sync::mpsc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    const EXPORT_PATH: &str = "/opentelemetry.proto.collector.trace.v1.TraceService/Export";
+
+    /// answers every gRPC call with an empty message, handing over the path and body it got
+    fn mock_receiver() -> (SocketAddr, mpsc::UnboundedReceiver<(String, Vec<u8>)>) {
+        let (exports, received) = mpsc::unbounded_channel();
+
+        let make_service = make_service_fn(move |_| {
+            let exports = exports.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let exports = exports.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        let _ = exports.send((path, body.to_vec()));
+
+                        let (mut sender, body) = Body::channel();
+                        tokio::spawn(async move {
+                            // a zero length, uncompressed message, then the status in trailers
+                            let _ = sender.send_data(vec![0u8; 5].into()).await;
+                            let mut trailers = hyper::HeaderMap::new();
+                            trailers.insert("grpc-status", "0".parse().unwrap());
+                            let _ = sender.send_trailers(trailers).await;
+                        });
+
+                        let mut response = Response::new(body);
+                        let content_type = "application/grpc".parse().unwrap();
+                        response.headers_mut().insert("content-type", content_type);
+                        Ok::<_, hyper::Error>(response)
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .http2_only(true)
+            .serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        (addr, received)
+    }
+
+    fn config(endpoint: String) -> AppConfig {
+        let defaults = concat!(env!("CARGO_MANIFEST_DIR"), "/src/config/env/defaults.yml");
+
+        let mut conf: AppConfig = Config::builder()
+            .add_source(File::with_name(defaults))
+            .build()
+            .and_then(|conf| conf.try_deserialize())
+            .unwrap();
+        conf.telemetry.enabled = true;
+        conf.telemetry.endpoint = endpoint;
+        conf
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_are_exported_over_otlp() {
+        let (addr, mut received) = mock_receiver();
+        let tracer = tracer(&config(format!("http://{}", addr))).unwrap();
+
+        tracer.in_span("otlp-export-test", |_| {});
+        shutdown().await;
+
+        let (path, body) = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .expect("no export within 5s")
+            .unwrap();
+        assert_eq!(path, EXPORT_PATH);
+        let name = b"otlp-export-test";
+        assert!(body.windows(name.len()).any(|window| window == name));
+    }
+
+    #[test]
+    fn requests_continue_the_trace_of_their_caller() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let tracer = opentelemetry::trace::TracerProvider::tracer(&provider, "test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+
+        let trace_id = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request");
+            set_remote_parent(&span, &headers);
+            span.context().span().span_context().trace_id()
+        });
+
+        assert_eq!(trace_id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+    }
+}